@@ -0,0 +1,164 @@
+//! Compact on-disk log of blocks and their context actions, so the benchmark can be replayed
+//! without a node.
+//!
+//! ```no_compile
+//! [magic: "MSACTLOG"][version: u32]
+//! [record_len: u32][record: bincode of BlockRecord]...
+//! ```
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use serde::{Serialize, Deserialize};
+
+use crate::channel::ContextAction;
+
+const MAGIC: &[u8; 8] = b"MSACTLOG";
+const VERSION: u32 = 1;
+
+/// Block with all context actions performed while applying it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BlockRecord {
+    pub block_hash: String,
+    pub level: u64,
+    pub actions: Vec<ContextAction>,
+}
+
+pub struct ActionLogWriter {
+    writer: BufWriter<File>,
+}
+
+impl ActionLogWriter {
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_be_bytes())?;
+        Ok(ActionLogWriter { writer })
+    }
+
+    pub fn append(&mut self, record: &BlockRecord) -> io::Result<()> {
+        let bytes = bincode::serialize(record).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        self.writer.write_all(&(bytes.len() as u32).to_be_bytes())?;
+        self.writer.write_all(&bytes)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+pub struct ActionLogReader {
+    reader: BufReader<File>,
+}
+
+impl ActionLogReader {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        let mut version = [0u8; 4];
+        reader.read_exact(&mut version)?;
+        if magic != *MAGIC || u32::from_be_bytes(version) != VERSION {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not an action log"));
+        }
+        Ok(ActionLogReader { reader })
+    }
+
+    fn read_record(&mut self) -> io::Result<Option<BlockRecord>> {
+        let mut len = [0u8; 4];
+        match self.reader.read_exact(&mut len) {
+            Ok(()) => (),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        let len = u32::from_be_bytes(len) as usize;
+        // do not trust the length with an allocation before the data is actually there
+        let mut bytes = Vec::new();
+        self.reader.by_ref().take(len as u64).read_to_end(&mut bytes)?;
+        if bytes.len() != len {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated record"));
+        }
+        bincode::deserialize(&bytes)
+            .map(Some)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+impl Iterator for ActionLogReader {
+    type Item = io::Result<BlockRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_record().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn test_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join("benchmark_action_log_tests");
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.join(name)
+    }
+
+    fn set_action(key: &str, value: u8) -> ContextAction {
+        ContextAction::Set {
+            context_hash: None,
+            block_hash: None,
+            operation_hash: None,
+            key: vec!["data".to_string(), key.to_string()],
+            value: vec![value],
+            value_as_json: None,
+            ignored: false,
+            start_time: 0.0,
+            end_time: 0.0,
+        }
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let path = test_path("roundtrip.log");
+        {
+            let mut writer = ActionLogWriter::create(&path).unwrap();
+            writer.append(&BlockRecord { block_hash: "b1".to_string(), level: 1, actions: vec![set_action("a", 1)] }).unwrap();
+            writer.append(&BlockRecord { block_hash: "b2".to_string(), level: 2, actions: vec![] }).unwrap();
+            writer.flush().unwrap();
+        }
+
+        let records = ActionLogReader::open(&path).unwrap().collect::<io::Result<Vec<_>>>().unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!((records[0].block_hash.as_str(), records[0].level), ("b1", 1));
+        assert_eq!((records[1].block_hash.as_str(), records[1].level), ("b2", 2));
+        assert!(records[1].actions.is_empty());
+        match &records[0].actions[..] {
+            [ContextAction::Set { key, value, .. }] => {
+                assert_eq!(key, &vec!["data".to_string(), "a".to_string()]);
+                assert_eq!(value, &vec![1u8]);
+            }
+            _ => panic!("unexpected actions"),
+        }
+    }
+
+    #[test]
+    fn test_bad_header_is_rejected() {
+        let path = test_path("bad_magic.log");
+        std::fs::write(&path, b"NOTALOG!\0\0\0\x01").unwrap();
+        assert_eq!(ActionLogReader::open(&path).err().unwrap().kind(), io::ErrorKind::InvalidData);
+
+        let path = test_path("bad_version.log");
+        let mut data = MAGIC.to_vec();
+        data.extend_from_slice(&(VERSION + 1).to_be_bytes());
+        std::fs::write(&path, data).unwrap();
+        assert_eq!(ActionLogReader::open(&path).err().unwrap().kind(), io::ErrorKind::InvalidData);
+
+        // truncated record
+        let path = test_path("truncated.log");
+        let mut data = MAGIC.to_vec();
+        data.extend_from_slice(&VERSION.to_be_bytes());
+        data.extend_from_slice(&u32::MAX.to_be_bytes());
+        std::fs::write(&path, data).unwrap();
+        assert!(ActionLogReader::open(&path).unwrap().next().unwrap().is_err());
+    }
+}
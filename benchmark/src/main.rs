@@ -1,6 +1,7 @@
 extern crate merkle;
 
 mod channel;
+mod action_log;
 
 use merkle::prelude::*;
 use std::sync::{Arc, RwLock};
@@ -11,6 +12,7 @@ use std::sync::atomic::{AtomicI64, Ordering};
 use timer;
 use chrono::Duration;
 use crate::channel::{ContextAction, ContextActionJson};
+use crate::action_log::{ActionLogReader, ActionLogWriter, BlockRecord};
 use std::io::{Cursor, Read};
 use serde_json::{Value, Map};
 use std::convert::TryInto;
@@ -50,25 +52,59 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             .default_value("4096")
             .help("Cycle length, logs the memory usage at every cycle")
         )
+        .arg(Arg::with_name("record")
+            .short("r")
+            .long("record")
+            .value_name("FILE")
+            .takes_value(true)
+            .help("Records fetched blocks and actions into the file, for later replay")
+        )
+        .arg(Arg::with_name("replay")
+            .long("replay")
+            .value_name("FILE")
+            .takes_value(true)
+            .conflicts_with("record")
+            .help("Replays blocks and actions recorded in the file, instead of fetching them from node")
+        )
+        .arg(Arg::with_name("verify")
+            .long("verify")
+            .help("Executes recorded reads (get, mem, dir_mem, fold) and reports results differing from node")
+        )
+        .arg(Arg::with_name("deferred-hashing")
+            .long("deferred-hashing")
+            .help("Hashes modified trees only when needed, instead of after every change")
+        )
         .get_matches();
 
     let node = matches.value_of("node").unwrap();
     let blocks_limit = matches.value_of("limit").unwrap().parse::<u64>().unwrap_or(25000);
     let cycle = matches.value_of("cycle").unwrap().parse::<u64>().unwrap_or(4096);
+    let verify = matches.is_present("verify");
+    let deferred_hashing = matches.is_present("deferred-hashing");
     let process_id = std::process::id();
 
+    if let Some(replay_file) = matches.value_of("replay") {
+        println!("replay {}, process id: {}", replay_file, process_id);
+        return run_replay(process_id, replay_file, cycle, verify, deferred_hashing).await;
+    }
+
     println!("node {}, limit {}, process id: {}", node, blocks_limit, process_id);
 
 
-    run_benchmark(process_id, node, blocks_limit, cycle).await
+    run_benchmark(process_id, node, blocks_limit, cycle, verify, deferred_hashing, matches.value_of("record")).await
 }
 
 
-async fn run_benchmark(process_id: u32, node: &str, blocks_limit: u64, cycle: u64) -> Result<(), Box<dyn std::error::Error>> {
+async fn run_benchmark(process_id: u32, node: &str, blocks_limit: u64, cycle: u64, verify: bool, deferred_hashing: bool, record_file: Option<&str>) -> Result<(), Box<dyn std::error::Error>> {
     let blocks_url = format!("{}/dev/chains/main/blocks?limit={}&from_block_id={}", node, blocks_limit + 10, blocks_limit);
     let db = Arc::new(RwLock::new(DB::new()));
-    let mut storage = MerkleStorage::new(db.clone());
+    let mut storage = MerkleStorage::new(db.clone()).with_deferred_hashing(deferred_hashing);
     let mut current_cycle = 0;
+    let mut mismatches = 0;
+    let mut recorder = match record_file {
+        Some(path) => Some(ActionLogWriter::create(path)?),
+        None => None,
+    };
 
     let mut blocks = reqwest::get(&blocks_url)
         .await?
@@ -88,79 +124,135 @@ async fn run_benchmark(process_id: u32, node: &str, blocks_limit: u64, cycle: u6
         drop(block);
 
 
-        let mut messages = reqwest::get(&actions_url)
+        let messages = reqwest::get(&actions_url)
             .await?
             .json::<Vec<ContextActionJson>>()
             .await?;
 
-        for msg in &messages {
-            match &msg.action {
-                ContextAction::Set { key, value, context_hash, ignored, .. } =>
-                    if !ignored {
-                        storage.set(key, value);
-                    }
-                ContextAction::Copy { to_key: key, from_key, context_hash, ignored, .. } =>
-                    if !ignored {
-                        storage.copy(from_key, key);
-                    }
-                ContextAction::Delete { key, context_hash, ignored, .. } =>
-                    if !ignored {
-                        storage.delete(key);
-                    }
-                ContextAction::RemoveRecursively { key, context_hash, ignored, .. } =>
-                    if !ignored {
-                        storage.delete(key);
-                    }
-                ContextAction::Commit {
-                    parent_context_hash, new_context_hash, block_hash: Some(block_hash),
-                    author, message, date, ..
-                } => {
-                    let date = *date as u64;
-                    let hash = storage.commit(date, author.to_owned(), message.to_owned()).unwrap();
-                    let commit_hash = hash[..].to_vec();
-                    assert_eq!(&commit_hash, new_context_hash,
-                               "Invalid context_hash for block: {}, expected: {}, but was: {}",
-                               HashType::BlockHash.bytes_to_string(block_hash),
-                               HashType::ContextHash.bytes_to_string(new_context_hash),
-                               HashType::ContextHash.bytes_to_string(&commit_hash),
-                    );
-                }
+        let block = BlockRecord {
+            block_hash: block_hash.to_string(),
+            level: block_level,
+            actions: messages.into_iter().map(|msg| msg.action).collect(),
+        };
+        if let Some(recorder) = recorder.as_mut() {
+            // flushed before the commit hash is checked, so that a failing block is recorded
+            recorder.append(&block)?;
+            recorder.flush()?;
+        }
+        mismatches += process_block(&mut storage, &block, process_id, cycle, verify, &mut current_cycle).await
+            .map_err(|err| err.to_string())?;
+    }
+    if verify {
+        println!("Verification finished with {} mismatches", mismatches);
+    }
+    Ok(())
+}
 
-                ContextAction::Checkout { context_hash, .. } => {
-                    let context_hash_arr: EntryHash = context_hash.as_slice().try_into().unwrap();
-                    storage.checkout(&context_hash_arr);
-                }
-                _ => (),
-            };
+async fn run_replay(process_id: u32, replay_file: &str, cycle: u64, verify: bool, deferred_hashing: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let db = Arc::new(RwLock::new(DB::new()));
+    let mut storage = MerkleStorage::new(db.clone()).with_deferred_hashing(deferred_hashing);
+    let mut current_cycle = 0;
+    let mut mismatches = 0;
+
+    for block in ActionLogReader::open(replay_file)? {
+        mismatches += process_block(&mut storage, &block?, process_id, cycle, verify, &mut current_cycle).await
+            .map_err(|err| err.to_string())?;
+    }
+    if verify {
+        println!("Verification finished with {} mismatches", mismatches);
+    }
+    Ok(())
+}
+
+/// Apply all actions of a block to the storage, checking commit hashes against the ones
+/// computed by node, and log the memory usage at every cycle. With `verify`, reads are
+/// executed as well and the number of results differing from node is returned. Fails on the
+/// first storage error.
+async fn process_block(storage: &mut MerkleStorage, block: &BlockRecord, process_id: u32, cycle: u64, verify: bool, current_cycle: &mut u64) -> Result<u64, MerkleError> {
+    let mut mismatches = 0;
+    for action in &block.actions {
+        match action {
+            ContextAction::Set { key, value, ignored: false, .. } => storage.set(key, value)?,
+            ContextAction::Copy { to_key, from_key, ignored: false, .. } => storage.copy(from_key, to_key)?,
+            ContextAction::Delete { key, ignored: false, .. } => storage.delete(key)?,
+            ContextAction::RemoveRecursively { key, ignored: false, .. } => storage.remove_recursively(key)?,
+            ContextAction::Commit {
+                new_context_hash, block_hash: Some(block_hash),
+                author, message, date, ..
+            } => {
+                let date = *date as u64;
+                let hash = storage.commit(date, author.to_owned(), message.to_owned())?;
+                let commit_hash = hash[..].to_vec();
+                assert_eq!(&commit_hash, new_context_hash,
+                           "Invalid context_hash for block: {}, expected: {}, but was: {}",
+                           HashType::BlockHash.bytes_to_string(block_hash),
+                           HashType::ContextHash.bytes_to_string(new_context_hash),
+                           HashType::ContextHash.bytes_to_string(&commit_hash),
+                );
+            }
+
+            ContextAction::Checkout { context_hash, .. } => {
+                let context_hash_arr: EntryHash = context_hash.as_slice().try_into().unwrap();
+                storage.checkout(context_hash_arr)?;
+            }
+            action if verify && !verify_read(storage, &block.block_hash, action) => {
+                mismatches += 1;
+            }
+            _ => (),
+        };
+    }
+    if block.level != 0 && block.level % cycle == 0 {
+        *current_cycle += 1;
+        println!("Memory stats at cycle: {}", current_cycle);
+        if let Ok(stats) = storage.get_merkle_stats() {
+            println!("{:#?}", stats)
         }
-        if block_level != 0 && block_level % cycle == 0 {
-            current_cycle += 1;
-            println!("Memory stats at cycle: {}", current_cycle);
-            match storage.get_merkle_stats() {
-                Ok(stats) => {
-                    println!("{:#?}", stats)
+        let pid = process_id.to_string();
+        if cfg!(target_os = "linux") || cfg!(target_os = "macos") {
+            let output = Command::new("ps")
+                .arg("-p")
+                .arg(&pid)
+                .arg("-o")
+                .arg("pid,%mem,rss,vsize")
+                .output().await;
+            match output {
+                Ok(output) => {
+                    println!("{}", String::from_utf8_lossy(&output.stdout))
                 }
-                Err(_) => {}
-            };
-            let pid = process_id.to_string();
-            if cfg!(target_os = "linux") || cfg!(target_os = "macos") {
-                let output = Command::new("ps")
-                    .arg("-p")
-                    .arg(&pid)
-                    .arg("-o")
-                    .arg("pid,%mem,rss,vsize")
-                    .output().await;
-                match output {
-                    Ok(output) => {
-                        println!("{}", String::from_utf8_lossy(&output.stdout))
-                    }
-                    Err(_) => {
-                        println!("Error executing PS")
-                    }
+                Err(_) => {
+                    println!("Error executing PS")
                 }
             }
         }
     }
-    Ok(())
+    Ok(mismatches)
 }
 
+/// Execute a recorded read against the storage and compare it with the result observed by node.
+/// Mismatches are reported with block hash and key. Actions other than reads are ignored.
+fn verify_read(storage: &mut MerkleStorage, block_hash: &str, action: &ContextAction) -> bool {
+    let (kind, key, mismatch) = match action {
+        ContextAction::Get { key, value, .. } => match storage.get(key) {
+            Ok(found) if &found == value => return true,
+            Ok(found) => ("get", key, format!("expected: {:?}, but was: {:?}", value, found)),
+            Err(err) => ("get", key, format!("expected: {:?}, but failed: {}", value, err)),
+        },
+        ContextAction::Mem { key, value, .. } => match storage.mem(key) {
+            Ok(found) if found == *value => return true,
+            Ok(found) => ("mem", key, format!("expected: {}, but was: {}", value, found)),
+            Err(err) => ("mem", key, format!("expected: {}, but failed: {}", value, err)),
+        },
+        ContextAction::DirMem { key, value, .. } => match storage.dir_mem(key) {
+            Ok(found) if found == *value => return true,
+            Ok(found) => ("dir_mem", key, format!("expected: {}, but was: {}", value, found)),
+            Err(err) => ("dir_mem", key, format!("expected: {}, but failed: {}", value, err)),
+        },
+        ContextAction::Fold { key, .. } => match storage.get_by_prefix(key) {
+            Ok(_) => return true,
+            Err(err) => ("fold", key, format!("failed: {}", err)),
+        },
+        _ => return true,
+    };
+    println!("Mismatch in {} for block: {}, key: {}, {}", kind, block_hash, key.join("/"), mismatch);
+    false
+}
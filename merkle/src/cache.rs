@@ -0,0 +1,132 @@
+use std::hash::Hash;
+
+use lru::LruCache;
+use serde::Serialize;
+
+/// Counters of a `BoundedCache`
+#[derive(Serialize, Debug, Clone, Copy, Default)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub entries: u64,
+    pub bytes: u64,
+}
+
+/// LRU cache bounded both by number of entries and approximate size of values in bytes.
+/// Sizes are provided by the caller on insert.
+pub(crate) struct BoundedCache<K: Hash + Eq, V: Clone> {
+    inner: LruCache<K, (V, u64)>,
+    max_entries: usize,
+    max_bytes: u64,
+    stats: CacheStats,
+}
+
+impl<K: Hash + Eq, V: Clone> BoundedCache<K, V> {
+    pub(crate) fn new(max_entries: usize, max_bytes: u64) -> Self {
+        BoundedCache {
+            inner: LruCache::unbounded(),
+            max_entries,
+            max_bytes,
+            stats: CacheStats::default(),
+        }
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.max_entries > 0 && self.max_bytes > 0
+    }
+
+    pub(crate) fn get(&mut self, key: &K) -> Option<V> {
+        match self.inner.get(key) {
+            Some((value, _)) => {
+                self.stats.hits += 1;
+                Some(value.clone())
+            }
+            None => {
+                self.stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    pub(crate) fn put(&mut self, key: K, value: V, size: u64) {
+        if !self.is_enabled() || size > self.max_bytes {
+            return;
+        }
+        if let Some((_, old_size)) = self.inner.put(key, (value, size)) {
+            self.stats.bytes -= old_size;
+        }
+        self.stats.bytes += size;
+
+        while self.inner.len() > self.max_entries || self.stats.bytes > self.max_bytes {
+            match self.inner.pop_lru() {
+                Some((_, (_, size))) => {
+                    self.stats.bytes -= size;
+                    self.stats.evictions += 1;
+                }
+                None => break,
+            }
+        }
+        self.stats.entries = self.inner.len() as u64;
+    }
+
+    pub(crate) fn remove(&mut self, key: &K) {
+        if let Some((_, size)) = self.inner.pop(key) {
+            self.stats.bytes -= size;
+            self.stats.entries = self.inner.len() as u64;
+        }
+    }
+
+    pub(crate) fn stats(&self) -> CacheStats {
+        self.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bounded_by_entries() {
+        let mut cache: BoundedCache<u32, u32> = BoundedCache::new(2, 100);
+        cache.put(1, 1, 1);
+        cache.put(2, 2, 1);
+        assert_eq!(cache.get(&1), Some(1));
+        cache.put(3, 3, 1);
+        // 2 was least recently used
+        assert_eq!(cache.get(&2), None);
+        assert_eq!(cache.get(&1), Some(1));
+        assert_eq!(cache.get(&3), Some(3));
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.evictions, stats.entries, stats.bytes), (3, 1, 1, 2, 2));
+    }
+
+    #[test]
+    fn test_bounded_by_bytes() {
+        let mut cache: BoundedCache<u32, u32> = BoundedCache::new(100, 10);
+        cache.put(1, 1, 4);
+        cache.put(2, 2, 4);
+        cache.put(3, 3, 4);
+        assert_eq!(cache.get(&1), None);
+        assert_eq!(cache.stats().bytes, 8);
+
+        // too big to be cached at all
+        cache.put(4, 4, 11);
+        assert_eq!(cache.get(&4), None);
+        assert_eq!(cache.get(&2), Some(2));
+
+        cache.remove(&2);
+        assert_eq!(cache.get(&2), None);
+        assert_eq!(cache.stats().bytes, 4);
+        assert_eq!(cache.stats().entries, 1);
+    }
+
+    #[test]
+    fn test_disabled() {
+        let mut cache: BoundedCache<u32, u32> = BoundedCache::new(0, 0);
+        cache.put(1, 1, 1);
+        assert_eq!(cache.get(&1), None);
+        assert_eq!(cache.stats().entries, 0);
+    }
+}
@@ -0,0 +1,264 @@
+//! # CommitLogStore
+//!
+//! Append-only store of `CommitLogSchema::Value` records, e.g. large blobs kept out of line, with
+//! only their `Location` stored elsewhere.
+//!
+//! Records are appended to segment files in the directory of the schema (named by
+//! `CommitLogDescriptor::name`), one frame per record holding the encoded value, see `segment`.
+//!
+//! A location is the offset of the frame within the whole log, so it stays valid for the life
+//! of the log. Segments are named by the offset of their first frame (`00000000000000000000.log`,
+//! ...). Once the active segment grows over `max_segment_size` a new one is started. A frame
+//! torn by a crash at the tail of the last segment is discarded on `open`.
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Seek, SeekFrom};
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::codec::{Decoder, Encoder};
+use crate::database::DBError;
+use crate::schema::CommitLogSchema;
+use crate::segment::{list_segments, read_frame, replay_segment, segment_path, write_frame, DEFAULT_MAX_SEGMENT_SIZE, FRAME_HEADER_LEN};
+
+/// Position of a record in a commit log
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Location {
+    /// Offset of the record's frame within the whole log
+    pub offset: u64,
+    /// Length of the encoded value
+    pub len: u32,
+}
+
+impl Location {
+    /// Offset of the record following this one
+    pub fn next_offset(&self) -> u64 {
+        self.offset + FRAME_HEADER_LEN + self.len as u64
+    }
+}
+
+pub struct CommitLogStore<S: CommitLogSchema> {
+    dir: PathBuf,
+    /// size of every segment by offset of its first frame
+    segments: BTreeMap<u64, u64>,
+    active: File,
+    max_segment_size: u64,
+    schema: PhantomData<S>,
+}
+
+impl<S: CommitLogSchema> CommitLogStore<S> {
+    /// Open commit log of schema `S` stored in `path`, creating it if it does not exist.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, DBError> {
+        Self::open_with_segment_size(path, DEFAULT_MAX_SEGMENT_SIZE)
+    }
+
+    /// Open commit log of schema `S` stored in `path`, starting a new segment whenever the active
+    /// one grows over `max_segment_size` bytes.
+    pub fn open_with_segment_size<P: AsRef<Path>>(path: P, max_segment_size: u64) -> Result<Self, DBError> {
+        let dir = path.as_ref().join(S::descriptor().name());
+        fs::create_dir_all(&dir)?;
+
+        let mut segments = BTreeMap::new();
+        for base_offset in list_segments(&dir)? {
+            let size = fs::metadata(segment_path(&dir, base_offset))?.len();
+            segments.insert(base_offset, size);
+        }
+        match segments.iter().next_back() {
+            Some((&base_offset, _)) => {
+                let size = replay_segment(&segment_path(&dir, base_offset), true, |_| Ok(()))?;
+                segments.insert(base_offset, size);
+            }
+            None => {
+                segments.insert(0, 0);
+            }
+        }
+
+        let (&active_offset, _) = segments.iter().next_back().unwrap();
+        let active = OpenOptions::new().create(true).append(true).open(segment_path(&dir, active_offset))?;
+
+        Ok(CommitLogStore {
+            dir,
+            segments,
+            active,
+            max_segment_size,
+            schema: PhantomData,
+        })
+    }
+
+    /// Directory holding the segment files
+    pub fn path(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Offset at which the next record will be appended, i.e. total size of the log
+    pub fn end_offset(&self) -> u64 {
+        let (base_offset, size) = self.segments.iter().next_back().unwrap();
+        base_offset + size
+    }
+
+    /// Append a record, returning its location. Use `flush` to make appended records durable.
+    /// Values encoded into more than `u32::MAX` bytes are rejected.
+    pub fn append(&mut self, value: &S::Value) -> Result<Location, DBError> {
+        let payload = value.encode()?;
+        let len = u32::try_from(payload.len()).map_err(|_| io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("value of {} bytes is too large", payload.len()),
+        ))?;
+
+        let (&base_offset, &size) = self.segments.iter().next_back().unwrap();
+        if size >= self.max_segment_size {
+            let next_offset = base_offset + size;
+            self.active = OpenOptions::new().create(true).append(true).open(segment_path(&self.dir, next_offset))?;
+            self.segments.insert(next_offset, 0);
+        }
+
+        let location = Location { offset: self.end_offset(), len };
+        let (_, size) = self.segments.iter_mut().next_back().unwrap();
+        *size += write_frame(&mut self.active, *size, &payload, false)?;
+        Ok(location)
+    }
+
+    /// Sync all appended records to disk
+    pub fn flush(&mut self) -> Result<(), DBError> {
+        self.active.sync_data()?;
+        Ok(())
+    }
+
+    /// Read record at `location`
+    pub fn get(&self, location: &Location) -> Result<S::Value, DBError> {
+        let mut reader = self.open_reader(location.offset)?;
+        match read_frame(&mut reader)? {
+            Some(payload) if payload.len() == location.len as usize => Ok(S::Value::decode(&payload)?),
+            _ => Err(DBError::NotFoundErr),
+        }
+    }
+
+    /// Read up to `count` consecutive records, starting with the one at `from`
+    pub fn get_range(&self, from: &Location, count: usize) -> Result<Vec<(Location, S::Value)>, DBError> {
+        let mut records = Vec::with_capacity(count);
+        let mut offset = from.offset;
+        while records.len() < count && offset < self.end_offset() {
+            let (base_offset, size) = self.segment_of(offset)?;
+            let mut reader = self.open_reader(offset)?;
+            while records.len() < count && offset < base_offset + size {
+                let payload = read_frame(&mut reader)?.ok_or(DBError::NotFoundErr)?;
+                let location = Location { offset, len: payload.len() as u32 };
+                records.push((location, S::Value::decode(&payload)?));
+                offset = location.next_offset();
+            }
+        }
+        Ok(records)
+    }
+
+    /// Segment (its first offset and size) containing `offset`
+    fn segment_of(&self, offset: u64) -> Result<(u64, u64), DBError> {
+        match self.segments.range(..=offset).next_back() {
+            Some((&base_offset, &size)) if offset < base_offset + size => Ok((base_offset, size)),
+            _ => Err(DBError::NotFoundErr),
+        }
+    }
+
+    fn open_reader(&self, offset: u64) -> Result<BufReader<File>, DBError> {
+        let (base_offset, _) = self.segment_of(offset)?;
+        let mut file = File::open(segment_path(&self.dir, base_offset))?;
+        file.seek(SeekFrom::Start(offset - base_offset))?;
+        Ok(BufReader::new(file))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::SchemaError;
+    use crate::segment::test_dir;
+
+    struct TestLog;
+
+    impl CommitLogSchema for TestLog {
+        type Value = String;
+
+        fn name() -> &'static str {
+            "test_log"
+        }
+    }
+
+    #[test]
+    fn test_append_and_get() {
+        let dir = test_dir("commit_log_append");
+        let mut log = CommitLogStore::<TestLog>::open(&dir).unwrap();
+        let a = log.append(&"a".to_string()).unwrap();
+        let b = log.append(&"bb".to_string()).unwrap();
+        assert_eq!(a, Location { offset: 0, len: 1 });
+        assert_eq!(b.offset, a.next_offset());
+        assert_eq!(log.get(&b).unwrap(), "bb");
+        assert_eq!(log.get(&a).unwrap(), "a");
+        assert!(log.get(&Location { offset: b.next_offset(), len: 1 }).is_err());
+        assert!(log.path().ends_with("test_log"));
+    }
+
+    #[test]
+    fn test_segments_and_range() {
+        let dir = test_dir("commit_log_segments");
+        let mut locations = Vec::new();
+        {
+            let mut log = CommitLogStore::<TestLog>::open_with_segment_size(&dir, 64).unwrap();
+            for i in 0..20 {
+                locations.push(log.append(&format!("value {}", i)).unwrap());
+            }
+            assert!(log.segments.len() > 1);
+            log.flush().unwrap();
+        }
+
+        let mut log = CommitLogStore::<TestLog>::open_with_segment_size(&dir, 64).unwrap();
+        assert_eq!(log.get(&locations[13]).unwrap(), "value 13");
+        let range = log.get_range(&locations[2], 10).unwrap();
+        assert_eq!(range.len(), 10);
+        assert_eq!(range[0], (locations[2], "value 2".to_string()));
+        assert_eq!(range[9], (locations[11], "value 11".to_string()));
+        assert_eq!(log.get_range(&locations[18], 10).unwrap().len(), 2);
+
+        let next = log.append(&"value 20".to_string()).unwrap();
+        assert_eq!(next.offset, locations[19].next_offset());
+        assert_eq!(log.get(&next).unwrap(), "value 20");
+    }
+
+    /// Value encoded into more bytes than a location can hold
+    struct OversizedValue;
+
+    impl Encoder for OversizedValue {
+        fn encode(&self) -> Result<Vec<u8>, SchemaError> {
+            // zeroed allocation, its pages are never touched
+            Ok(vec![0u8; u32::MAX as usize + 1])
+        }
+    }
+
+    impl Decoder for OversizedValue {
+        fn decode(_bytes: &[u8]) -> Result<Self, SchemaError> {
+            Ok(OversizedValue)
+        }
+    }
+
+    struct OversizedLog;
+
+    impl CommitLogSchema for OversizedLog {
+        type Value = OversizedValue;
+
+        fn name() -> &'static str {
+            "oversized_log"
+        }
+    }
+
+    #[test]
+    fn test_oversized_value_is_rejected() {
+        let dir = test_dir("commit_log_oversized");
+        let mut log = CommitLogStore::<OversizedLog>::open(&dir).unwrap();
+        match log.append(&OversizedValue) {
+            Err(DBError::IOError { error }) => assert_eq!(error.kind(), io::ErrorKind::InvalidInput),
+            _ => panic!("oversized value must be rejected"),
+        }
+        assert_eq!(log.end_offset(), 0);
+    }
+}
@@ -2,15 +2,31 @@ use crate::schema::KeyValueSchema;
 use crate::codec::{SchemaError, Encoder, Decoder};
 use failure::Fail;
 use std::marker::PhantomData;
+use std::ops::Bound;
 use crate::db_iterator;
 use std::collections::{HashMap, BTreeMap};
 use crate::db_iterator::{DBIterator, DBIterationHandler};
 use crate::ivec::IVec;
 use serde::{Serialize,Deserialize};
 
+/// Column family used by `Batch::insert` and `Batch::remove`
+pub const DEFAULT_COLUMN_FAMILY: &str = "default";
+
+/// Single write of a `Batch`, in the column family it names
+#[derive(Debug, Clone)]
+pub(crate) enum BatchOp {
+    Put(String, IVec, IVec),
+    PutIfAbsent(String, IVec, IVec),
+    Delete(String, IVec),
+    /// Delete all keys in `from..to`
+    DeleteRange(String, IVec, IVec),
+}
+
+/// Writes applied together by `write_batch`, in the order they were added. If any of them
+/// fails (e.g. `insert_if_absent` of an existing key) none of them is applied.
 #[derive(Debug, Default, Clone)]
 pub struct Batch {
-    pub(crate) writes: HashMap<IVec, Option<IVec>>,
+    pub(crate) ops: Vec<BatchOp>,
 }
 
 impl Batch {
@@ -20,7 +36,16 @@ impl Batch {
             K: Into<IVec>,
             V: Into<IVec>,
     {
-        self.writes.insert(key.into(), Some(value.into()));
+        self.insert_cf(DEFAULT_COLUMN_FAMILY, key, value);
+    }
+
+    /// Set a key to a new value, failing the batch if the key already exists
+    pub fn insert_if_absent<K, V>(&mut self, key: K, value: V)
+        where
+            K: Into<IVec>,
+            V: Into<IVec>,
+    {
+        self.insert_if_absent_cf(DEFAULT_COLUMN_FAMILY, key, value);
     }
 
     /// Remove a key
@@ -28,7 +53,57 @@ impl Batch {
         where
             K: Into<IVec>,
     {
-        self.writes.insert(key.into(), None);
+        self.remove_cf(DEFAULT_COLUMN_FAMILY, key);
+    }
+
+    /// Remove all keys in `from..to`
+    pub fn remove_range<K>(&mut self, from: K, to: K)
+        where
+            K: Into<IVec>,
+    {
+        self.remove_range_cf(DEFAULT_COLUMN_FAMILY, from, to);
+    }
+
+    /// Set a key in column family `name` to a new value
+    pub fn insert_cf<K, V>(&mut self, name: &str, key: K, value: V)
+        where
+            K: Into<IVec>,
+            V: Into<IVec>,
+    {
+        self.ops.push(BatchOp::Put(name.to_string(), key.into(), value.into()));
+    }
+
+    /// Set a key in column family `name` to a new value, failing the batch if the key already exists
+    pub fn insert_if_absent_cf<K, V>(&mut self, name: &str, key: K, value: V)
+        where
+            K: Into<IVec>,
+            V: Into<IVec>,
+    {
+        self.ops.push(BatchOp::PutIfAbsent(name.to_string(), key.into(), value.into()));
+    }
+
+    /// Remove a key from column family `name`
+    pub fn remove_cf<K>(&mut self, name: &str, key: K)
+        where
+            K: Into<IVec>,
+    {
+        self.ops.push(BatchOp::Delete(name.to_string(), key.into()));
+    }
+
+    /// Remove all keys in `from..to` from column family `name`
+    pub fn remove_range_cf<K>(&mut self, name: &str, from: K, to: K)
+        where
+            K: Into<IVec>,
+    {
+        self.ops.push(BatchOp::DeleteRange(name.to_string(), from.into(), to.into()));
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
     }
 }
 
@@ -38,15 +113,29 @@ impl From<SchemaError> for DBError {
     }
 }
 
+impl From<std::io::Error> for DBError {
+    fn from(error: std::io::Error) -> Self {
+        DBError::IOError { error }
+    }
+}
+
 #[derive(Debug, Fail)]
 pub enum DBError {
     #[fail(display = "Not found error")]
     NotFoundErr,
 
+    #[fail(display = "Key already exists error")]
+    KeyAlreadyExists,
+
     #[fail(display = "Schema error: {}", error)]
     SchemaError {
         error: SchemaError
     },
+
+    #[fail(display = "IO error: {}", error)]
+    IOError {
+        error: std::io::Error
+    },
 }
 
 impl slog::Value for DBError {
@@ -55,10 +144,17 @@ impl slog::Value for DBError {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct DBStats {
     pub db_size: usize,
-    pub keys : usize
+    pub keys : usize,
+    pub column_families: BTreeMap<String, ColumnFamilyStats>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ColumnFamilyStats {
+    pub db_size: usize,
+    pub keys : usize,
 }
 
 
@@ -77,7 +173,8 @@ pub trait KeyValueStoreWithSchema<S: KeyValueSchema> {
     /// * `key` - Value of key specified by schema
     fn delete(&mut self, key: &S::Key) -> Result<(), DBError>;
 
-    /// Insert key value pair into the database, overriding existing value if exists.
+    /// Insert key value pair into the database, combining it with the existing value using
+    /// `KeyValueSchema::merge_values`. By default the existing value is overridden.
     ///
     /// # Arguments
     /// * `key` - Value of key specified by schema
@@ -93,14 +190,14 @@ pub trait KeyValueStoreWithSchema<S: KeyValueSchema> {
     /// Read all entries in database.
     ///
     /// # Arguments
-    /// * `mode` - Reading mode, specified by RocksDB, From start to end, from end to start, or from
-    /// arbitrary position to end.
+    /// * `mode` - Reading mode, specified by RocksDB, From start to end, from end to start, from
+    ///   arbitrary position to end/start, or within a range of keys.
     fn iterator(&self, mode: IteratorMode<S>) -> Result<IteratorWithSchema<S>, DBError>;
 
-    /// Starting from given key, read all entries to the end.
+    /// Read all entries, whose encoded key starts with encoded given key.
     ///
     /// # Arguments
-    /// * `key` - Key (specified by schema), from which to start reading entries
+    /// * `key` - Key (specified by schema), prefix of all read entries
     fn prefix_iterator(&self, key: &S::Key) -> Result<IteratorWithSchema<S>, DBError>;
 
     /// Check, if database contains given key
@@ -116,6 +213,27 @@ pub trait KeyValueStoreWithSchema<S: KeyValueSchema> {
     /// * `value` - Value to be inserted associated with given key, specified by schema
     fn put_batch(&self, batch: &mut Batch, key: &S::Key, value: &S::Value) -> Result<(), DBError>;
 
+    /// Insert new key value pair into WriteBatch, the whole batch fails if the key already exists
+    /// when it is written.
+    ///
+    /// # Arguments
+    /// * `key` - Value of key specified by schema
+    /// * `value` - Value to be inserted associated with given key, specified by schema
+    fn put_if_absent_batch(&self, batch: &mut Batch, key: &S::Key, value: &S::Value) -> Result<(), DBError>;
+
+    /// Insert deletion of given key into WriteBatch.
+    ///
+    /// # Arguments
+    /// * `key` - Value of key specified by schema
+    fn delete_batch(&self, batch: &mut Batch, key: &S::Key) -> Result<(), DBError>;
+
+    /// Insert deletion of all keys whose encoding is within `from..to` into WriteBatch.
+    ///
+    /// # Arguments
+    /// * `from` - First key to be deleted, specified by schema
+    /// * `to` - Key after the last key to be deleted, specified by schema
+    fn delete_range_batch(&self, batch: &mut Batch, from: &S::Key, to: &S::Key) -> Result<(), DBError>;
+
     /// Write batch into DB atomically
     ///
     /// # Arguments
@@ -144,11 +262,14 @@ impl<'a, S: KeyValueSchema> Iterator for IteratorWithSchema<'a, S> {
     }
 }
 
-pub struct DB {
+/// Namespace of keys in `DB`, each `KeyValueSchema` uses the column family named by
+/// `KeyValueSchema::name`
+#[derive(Default)]
+pub struct ColumnFamily {
     pub(crate) inner: BTreeMap<IVec, IVec>
 }
 
-impl DB {
+impl ColumnFamily {
     pub fn db_size(&self) -> usize {
         let mut byte_count = 0;
 
@@ -159,26 +280,145 @@ impl DB {
 
         byte_count
     }
+
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    /// Remove all keys in `from..to`
+    pub(crate) fn remove_range(&mut self, from: &IVec, to: &IVec) {
+        if from >= to {
+            return;
+        }
+        let keys = self.inner.range(from.clone()..to.clone()).map(|(k, _)| k.clone()).collect::<Vec<_>>();
+        for key in keys {
+            self.inner.remove(&key);
+        }
+    }
+
+    pub fn stats(&self) -> ColumnFamilyStats {
+        ColumnFamilyStats {
+            db_size: self.db_size(),
+            keys: self.len(),
+        }
+    }
+}
+
+pub struct DB {
+    pub(crate) families: BTreeMap<String, ColumnFamily>
+}
+
+impl DB {
+    pub fn db_size(&self) -> usize {
+        self.families.values().map(ColumnFamily::db_size).sum()
+    }
 }
 
 impl DB {
     pub fn new() -> Self {
         DB {
-            inner: BTreeMap::new()
+            families: BTreeMap::new()
+        }
+    }
+
+    /// Get column family `name`, creating it if it does not exist
+    pub fn open_column_family(&mut self, name: &str) -> &mut ColumnFamily {
+        if !self.families.contains_key(name) {
+            self.families.insert(name.to_string(), ColumnFamily::default());
         }
+        self.families.get_mut(name).unwrap()
     }
 
-    pub(crate) fn apply_batch(&mut self, batch: Batch) {
-        self.inner.extend(batch.writes.iter().map(|(k, v)| {
-            match v {
-                None => {
-                    (k.clone(), IVec::default())
+    /// Get column family `name`, if it exists
+    pub fn column_family(&self, name: &str) -> Option<&ColumnFamily> {
+        self.families.get(name)
+    }
+
+    /// Names of all existing column families, in order
+    pub fn column_family_names(&self) -> Vec<&str> {
+        self.families.keys().map(String::as_str).collect()
+    }
+
+    /// Remove column family `name` with all its keys. Returns false if it did not exist.
+    pub fn drop_column_family(&mut self, name: &str) -> bool {
+        self.families.remove(name).is_some()
+    }
+
+    /// Encoded result of merging `value` into the value stored under encoded `key`, see
+    /// `KeyValueSchema::merge_values`.
+    pub(crate) fn merged_value<S: KeyValueSchema>(&self, key: &[u8], value: &S::Value) -> Result<Vec<u8>, DBError> {
+        let existing = match self.column_family(S::name()).and_then(|family| family.inner.get(key)) {
+            Some(existing) => Some(S::Value::decode(existing)?),
+            None => None,
+        };
+        match S::merge_values(existing, value) {
+            Some(merged) => Ok(merged.encode()?),
+            None => Ok(value.encode()?),
+        }
+    }
+
+    /// Check that all ops of `batch` can be applied, taking into account the effect of the ops
+    /// before them, without modifying the database.
+    pub(crate) fn check_batch(&self, batch: &Batch) -> Result<(), DBError> {
+        // keys written by the batch so far and whether they exist afterwards
+        let mut written: HashMap<(&str, &IVec), bool> = HashMap::new();
+        let mut removed_ranges: Vec<(&str, &IVec, &IVec)> = Vec::new();
+
+        for op in &batch.ops {
+            match op {
+                BatchOp::Put(name, key, _) => {
+                    written.insert((name.as_str(), key), true);
+                }
+                BatchOp::Delete(name, key) => {
+                    written.insert((name.as_str(), key), false);
                 }
-                Some(v) => {
-                    (k.clone(), v.clone())
+                BatchOp::DeleteRange(name, from, to) => {
+                    written.retain(|(n, k), _| !(*n == name.as_str() && *k >= from && *k < to));
+                    removed_ranges.push((name.as_str(), from, to));
+                }
+                BatchOp::PutIfAbsent(name, key, _) => {
+                    let exists = match written.get(&(name.as_str(), key)) {
+                        Some(exists) => *exists,
+                        None => {
+                            let removed = removed_ranges.iter().any(|(n, from, to)| *n == name.as_str() && key >= *from && key < *to);
+                            !removed && matches!(self.column_family(name), Some(family) if family.inner.contains_key(key))
+                        }
+                    };
+                    if exists {
+                        return Err(DBError::KeyAlreadyExists);
+                    }
+                    written.insert((name.as_str(), key), true);
                 }
             }
-        }))
+        }
+        Ok(())
+    }
+
+    /// Apply all ops of `batch` in order, fails without modifying the database if `check_batch` does.
+    pub(crate) fn apply_batch(&mut self, batch: Batch) -> Result<(), DBError> {
+        self.check_batch(&batch)?;
+        for op in batch.ops {
+            match op {
+                BatchOp::Put(name, key, value) | BatchOp::PutIfAbsent(name, key, value) => {
+                    self.open_column_family(&name).inner.insert(key, value);
+                }
+                BatchOp::Delete(name, key) => {
+                    if let Some(family) = self.families.get_mut(&name) {
+                        family.inner.remove(&key);
+                    }
+                }
+                BatchOp::DeleteRange(name, from, to) => {
+                    if let Some(family) = self.families.get_mut(&name) {
+                        family.remove_range(&from, &to);
+                    }
+                }
+            }
+        }
+        Ok(())
     }
 }
 
@@ -188,98 +428,261 @@ pub enum Direction {
     Reverse,
 }
 
-/// Database iterator with schema mode, from start to end, from end to start or from specific key to end/start
+impl From<Direction> for db_iterator::Direction {
+    fn from(direction: Direction) -> Self {
+        match direction {
+            Direction::Forward => db_iterator::Direction::Forward,
+            Direction::Reverse => db_iterator::Direction::Reverse,
+        }
+    }
+}
+
+/// Database iterator with schema mode, from start to end, from end to start, from specific key to end/start
+/// or within a range of keys, bounds being inclusive or exclusive
 pub enum IteratorMode<'a, S: KeyValueSchema> {
     Start,
     End,
     From(&'a S::Key, Direction),
+    Range(Bound<&'a S::Key>, Bound<&'a S::Key>, Direction),
+}
+
+fn encode_bound<K: Encoder>(bound: Bound<&K>) -> Result<Bound<IVec>, SchemaError> {
+    Ok(match bound {
+        Bound::Included(key) => Bound::Included(key.encode()?.into()),
+        Bound::Excluded(key) => Bound::Excluded(key.encode()?.into()),
+        Bound::Unbounded => Bound::Unbounded,
+    })
 }
 
 impl<S: KeyValueSchema> KeyValueStoreWithSchema<S> for DB {
     fn put(&mut self, key: &S::Key, value: &S::Value) -> Result<(), DBError> {
-        let key = key.encode()?;
+        let key = IVec::from(key.encode()?);
         let value = value.encode()?;
-        self.inner.insert(key.into(), value.into());
+        let family = self.open_column_family(S::name());
+        if family.inner.contains_key(&key) {
+            return Err(DBError::KeyAlreadyExists);
+        }
+        family.inner.insert(key, value.into());
         Ok(())
     }
 
     fn delete(&mut self, key: &S::Key) -> Result<(), DBError> {
         let key = key.encode()?;
-        self.inner.remove(&IVec::from(key));
+        if let Some(family) = self.families.get_mut(S::name()) {
+            family.inner.remove(&IVec::from(key));
+        }
         Ok(())
     }
 
     fn merge(&mut self, key: &S::Key, value: &<S as KeyValueSchema>::Value) -> Result<(), DBError> {
         let key = key.encode()?;
-        let value = value.encode()?;
-        self.inner.insert(key.into(), value.into());
+        let value = self.merged_value::<S>(&key, value)?;
+        self.open_column_family(S::name()).inner.insert(key.into(), value.into());
         Ok(())
     }
 
     fn get(&self, key: &S::Key) -> Result<Option<S::Value>, DBError> {
         let key = key.encode()?;
 
-        match self.inner.get(&IVec::from(key)) {
+        match self.column_family(S::name()).and_then(|family| family.inner.get(&IVec::from(key))) {
             Some(v) => {
                 Ok(Some(S::Value::decode(v)?))
             }
             None => {
-                Err(DBError::NotFoundErr)
+                Ok(None)
             }
         }
     }
 
     fn iterator(&self, mode: IteratorMode<S>) -> Result<IteratorWithSchema<S>, DBError> {
-        let iter = match mode {
+        let family = self.column_family(S::name());
+        let mode = match mode {
             IteratorMode::Start => {
-                self.iter(db_iterator::IteratorMode::Start)
+                db_iterator::IteratorMode::Start
             }
             IteratorMode::End => {
-                self.iter(db_iterator::IteratorMode::End)
+                db_iterator::IteratorMode::End
             }
             IteratorMode::From(key, direction) => {
                 let key = key.encode()?;
-                match direction {
-                    Direction::Forward => {
-                        self.iter(db_iterator::IteratorMode::From(key.into(), db_iterator::Direction::Forward))
-                    }
-                    Direction::Reverse => {
-                        self.iter(db_iterator::IteratorMode::From(key.into(), db_iterator::Direction::Reverse))
-                    }
-                }
+                db_iterator::IteratorMode::From(key.into(), direction.into())
             }
+            IteratorMode::Range(from, to, direction) => {
+                db_iterator::IteratorMode::Range(encode_bound(from)?, encode_bound(to)?, direction.into())
+            }
+        };
+        let iter = match family {
+            Some(family) => family.iter(mode),
+            None => DBIterator::empty(),
         };
         Ok(IteratorWithSchema(iter, PhantomData))
     }
 
     fn prefix_iterator(&self, key: &S::Key) -> Result<IteratorWithSchema<S>, DBError> {
         let key = key.encode()?;
-        let iter = self.scan_prefix(&IVec::from(key));
+        let iter = match self.column_family(S::name()) {
+            Some(family) => family.scan_prefix(&IVec::from(key)),
+            None => DBIterator::empty(),
+        };
         Ok(IteratorWithSchema(iter, PhantomData))
     }
 
     fn contains(&self, key: &S::Key) -> Result<bool, DBError> {
         let key = key.encode()?;
-        Ok(self.inner.contains_key(&IVec::from(key)))
+        Ok(matches!(self.column_family(S::name()), Some(family) if family.inner.contains_key(&IVec::from(key))))
     }
 
     fn put_batch(&self, batch: &mut Batch, key: &S::Key, value: &S::Value) -> Result<(), DBError> {
         let key = key.encode()?;
         let value = value.encode()?;
-        batch.insert(key, value);
+        batch.insert_cf(S::name(), key, value);
         Ok(())
     }
 
-    fn write_batch(&mut self, batch: Batch) -> Result<(), DBError> {
-        self.apply_batch(batch);
+    fn put_if_absent_batch(&self, batch: &mut Batch, key: &S::Key, value: &S::Value) -> Result<(), DBError> {
+        let key = key.encode()?;
+        let value = value.encode()?;
+        batch.insert_if_absent_cf(S::name(), key, value);
+        Ok(())
+    }
+
+    fn delete_batch(&self, batch: &mut Batch, key: &S::Key) -> Result<(), DBError> {
+        let key = key.encode()?;
+        batch.remove_cf(S::name(), key);
         Ok(())
     }
 
+    fn delete_range_batch(&self, batch: &mut Batch, from: &S::Key, to: &S::Key) -> Result<(), DBError> {
+        let from = from.encode()?;
+        let to = to.encode()?;
+        batch.remove_range_cf(S::name(), from, to);
+        Ok(())
+    }
+
+    fn write_batch(&mut self, batch: Batch) -> Result<(), DBError> {
+        self.apply_batch(batch)
+    }
+
     fn get_mem_use_stats(&self) -> Result<DBStats, DBError> {
         Ok(DBStats {
             db_size: self.db_size(),
-            keys : self.inner.len()
+            keys : self.families.values().map(ColumnFamily::len).sum(),
+            column_families: self.families.iter().map(|(name, family)| (name.clone(), family.stats())).collect(),
         })
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TestSchema;
+
+    impl KeyValueSchema for TestSchema {
+        type Key = String;
+        type Value = String;
+
+        fn name() -> &'static str {
+            "test_schema"
+        }
+    }
+
+    /// Schema merging values by concatenation
+    struct AppendSchema;
+
+    impl KeyValueSchema for AppendSchema {
+        type Key = String;
+        type Value = String;
+
+        fn name() -> &'static str {
+            "append_schema"
+        }
+
+        fn merge_values(existing: Option<Self::Value>, value: &Self::Value) -> Option<Self::Value> {
+            Some(existing.unwrap_or_default() + value)
+        }
+    }
+
+    fn put<S: KeyValueSchema<Key = String, Value = String>>(db: &mut DB, key: &str, value: &str) {
+        KeyValueStoreWithSchema::<S>::put(db, &key.to_string(), &value.to_string()).unwrap();
+    }
+
+    fn get<S: KeyValueSchema<Key = String, Value = String>>(db: &DB, key: &str) -> Option<String> {
+        KeyValueStoreWithSchema::<S>::get(db, &key.to_string()).unwrap()
+    }
+
+    #[test]
+    fn test_rejected_batch_is_not_applied() {
+        let mut db = DB::new();
+        put::<TestSchema>(&mut db, "a", "1");
+
+        let mut batch = Batch::default();
+        batch.insert_cf(TestSchema::name(), "b", "2");
+        batch.remove_cf(TestSchema::name(), "a");
+        batch.insert_if_absent_cf(TestSchema::name(), "b", "3");
+        assert!(matches!(KeyValueStoreWithSchema::<TestSchema>::write_batch(&mut db, batch), Err(DBError::KeyAlreadyExists)));
+        assert_eq!(get::<TestSchema>(&db, "a"), Some("1".to_string()));
+        assert_eq!(get::<TestSchema>(&db, "b"), None);
+
+        // a key removed earlier in the batch can be inserted again
+        let mut batch = Batch::default();
+        batch.remove_range_cf(TestSchema::name(), "a", "b");
+        batch.insert_if_absent_cf(TestSchema::name(), "a", "4");
+        KeyValueStoreWithSchema::<TestSchema>::write_batch(&mut db, batch).unwrap();
+        assert_eq!(get::<TestSchema>(&db, "a"), Some("4".to_string()));
+    }
+
+    #[test]
+    fn test_inverted_delete_range_removes_nothing() {
+        let mut db = DB::new();
+        for key in &["a", "b", "c"] {
+            put::<TestSchema>(&mut db, key, key);
+        }
+
+        let mut batch = Batch::default();
+        batch.remove_range_cf(TestSchema::name(), "c", "a");
+        batch.insert_if_absent_cf(TestSchema::name(), "b", "x");
+        assert!(matches!(KeyValueStoreWithSchema::<TestSchema>::write_batch(&mut db, batch), Err(DBError::KeyAlreadyExists)));
+
+        let mut batch = Batch::default();
+        batch.remove_range_cf(TestSchema::name(), "c", "a");
+        KeyValueStoreWithSchema::<TestSchema>::write_batch(&mut db, batch).unwrap();
+        assert_eq!(db.column_family(TestSchema::name()).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_drop_column_family() {
+        let mut db = DB::new();
+        put::<TestSchema>(&mut db, "a", "1");
+        put::<AppendSchema>(&mut db, "a", "2");
+        assert_eq!(db.column_family_names(), vec!["append_schema", "test_schema"]);
+
+        assert!(db.drop_column_family(TestSchema::name()));
+        assert!(!db.drop_column_family(TestSchema::name()));
+        assert_eq!(db.column_family_names(), vec!["append_schema"]);
+        assert_eq!(get::<TestSchema>(&db, "a"), None);
+        assert_eq!(KeyValueStoreWithSchema::<TestSchema>::iterator(&db, IteratorMode::Start).unwrap().count(), 0);
+        assert_eq!(get::<AppendSchema>(&db, "a"), Some("2".to_string()));
+
+        // the column family is created again by the next write
+        put::<TestSchema>(&mut db, "a", "3");
+        assert_eq!(get::<TestSchema>(&db, "a"), Some("3".to_string()));
+    }
+
+    #[test]
+    fn test_merge_with_absent_value() {
+        let mut db = DB::new();
+        let (key, a, b) = ("k".to_string(), "a".to_string(), "b".to_string());
+
+        KeyValueStoreWithSchema::<AppendSchema>::merge(&mut db, &key, &a).unwrap();
+        assert_eq!(get::<AppendSchema>(&db, "k"), Some("a".to_string()));
+        KeyValueStoreWithSchema::<AppendSchema>::merge(&mut db, &key, &b).unwrap();
+        assert_eq!(get::<AppendSchema>(&db, "k"), Some("ab".to_string()));
+
+        // without a merge operator the value is replaced
+        KeyValueStoreWithSchema::<TestSchema>::merge(&mut db, &key, &a).unwrap();
+        KeyValueStoreWithSchema::<TestSchema>::merge(&mut db, &key, &b).unwrap();
+        assert_eq!(get::<TestSchema>(&db, "k"), Some("b".to_string()));
+    }
+}
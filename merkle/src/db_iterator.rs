@@ -1,6 +1,8 @@
-use crate::schema::KeyValueSchema;
+use std::collections::btree_map;
+use std::ops::Bound;
+
 use crate::ivec::IVec;
-use crate::database::DB;
+use crate::database::ColumnFamily;
 
 
 /// Database iterator direction
@@ -11,25 +13,74 @@ pub enum Direction {
     Reverse,
 }
 
+/// Database iterator mode, from start to end, from end to start, from specific key to end/start
+/// or within a range of keys in given direction
 #[derive(Clone)]
 pub enum IteratorMode {
     Start,
     End,
     From(IVec, Direction),
+    Range(Bound<IVec>, Bound<IVec>, Direction),
 }
 
+/// Cursor over a range of entries of a `ColumnFamily`
 pub struct DBIterator<'a> {
-    raw: &'a DB,
-    mode: IteratorMode,
+    inner: Option<btree_map::Range<'a, IVec, IVec>>,
+    direction: Direction,
 }
 
 impl<'a> DBIterator<'a> {
-    pub(crate) fn new(raw: &'a DB, mode: IteratorMode) -> Self {
+    /// Iterator over a column family that does not exist
+    pub(crate) fn empty() -> Self {
+        DBIterator {
+            inner: None,
+            direction: Direction::Forward,
+        }
+    }
+
+    pub(crate) fn new(raw: &'a ColumnFamily, mode: IteratorMode) -> Self {
+        let (from, to, direction) = match mode {
+            IteratorMode::Start => (Bound::Unbounded, Bound::Unbounded, Direction::Forward),
+            IteratorMode::End => (Bound::Unbounded, Bound::Unbounded, Direction::Reverse),
+            IteratorMode::From(key, Direction::Forward) => (Bound::Included(key), Bound::Unbounded, Direction::Forward),
+            IteratorMode::From(key, Direction::Reverse) => (Bound::Unbounded, Bound::Included(key), Direction::Reverse),
+            IteratorMode::Range(from, to, direction) => (from, to, direction),
+        };
+
+        let inner = if is_empty_range(&from, &to) {
+            // `BTreeMap::range` panics on inverted ranges
+            raw.inner.range((Bound::Included(IVec::default()), Bound::Excluded(IVec::default())))
+        } else {
+            raw.inner.range((from, to))
+        };
+
         DBIterator {
-            raw,
-            mode,
+            inner: Some(inner),
+            direction,
+        }
+    }
+}
+
+fn is_empty_range(from: &Bound<IVec>, to: &Bound<IVec>) -> bool {
+    match (from, to) {
+        (Bound::Included(from), Bound::Included(to)) => from > to,
+        (Bound::Included(from), Bound::Excluded(to))
+        | (Bound::Excluded(from), Bound::Included(to))
+        | (Bound::Excluded(from), Bound::Excluded(to)) => from >= to,
+        _ => false,
+    }
+}
+
+/// Smallest key greater than all keys starting with `prefix`, if there is one
+fn prefix_end(prefix: &[u8]) -> Bound<IVec> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return Bound::Excluded(IVec::from(end));
         }
     }
+    Bound::Unbounded
 }
 
 
@@ -37,39 +88,86 @@ impl<'a> Iterator for DBIterator<'a> {
     type Item = (IVec, IVec);
 
     fn next(&mut self) -> Option<Self::Item> {
-        match &self.mode {
-            IteratorMode::Start => {
-                self.raw.inner.iter().next().map(|(k, v)| { (k.clone(), v.clone()) })
-            }
-            IteratorMode::End => {
-                self.raw.inner.iter().last().map(|(k, v)| { (k.clone(), v.clone()) })
-            }
-            IteratorMode::From(k, direction) => {
-                let key = k.to_vec();
-                match direction {
-                    Direction::Forward => {
-                        self.raw.inner.range(IVec::from(key)..).next().map(|(k, v)| { (k.clone(), v.clone()) })
-                    }
-                    Direction::Reverse => {
-                        self.raw.inner.range(IVec::from(key)..).last().map(|(k, v)| { (k.clone(), v.clone()) })
-                    }
-                }
-            }
-        }
+        let inner = self.inner.as_mut()?;
+        let next = match self.direction {
+            Direction::Forward => inner.next(),
+            Direction::Reverse => inner.next_back(),
+        };
+        next.map(|(k, v)| (k.clone(), v.clone()))
     }
 }
 
 pub trait DBIterationHandler {
-    fn iter(&self, mode: IteratorMode) -> DBIterator;
-    fn scan_prefix(&self, prefix: &[u8]) -> DBIterator;
+    fn iter(&self, mode: IteratorMode) -> DBIterator<'_>;
+    fn scan_prefix(&self, prefix: &[u8]) -> DBIterator<'_>;
 }
 
-impl DBIterationHandler for DB {
-    fn iter(&self, mode: IteratorMode) -> DBIterator {
+impl DBIterationHandler for ColumnFamily {
+    fn iter(&self, mode: IteratorMode) -> DBIterator<'_> {
         DBIterator::new(self, mode)
     }
 
-    fn scan_prefix(&self, prefix: &[u8]) -> DBIterator {
-        DBIterator::new(self, IteratorMode::From(IVec::from(prefix), Direction::Forward))
+    fn scan_prefix(&self, prefix: &[u8]) -> DBIterator<'_> {
+        DBIterator::new(self, IteratorMode::Range(Bound::Included(IVec::from(prefix)), prefix_end(prefix), Direction::Forward))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_db() -> ColumnFamily {
+        let mut db = ColumnFamily::default();
+        for key in &[vec![1u8], vec![2u8], vec![2u8, 0u8], vec![2u8, 255u8], vec![3u8], vec![255u8], vec![255u8, 1u8]] {
+            db.inner.insert(IVec::from(key.clone()), IVec::from(key.clone()));
+        }
+        db
+    }
+
+    fn keys(iter: DBIterator) -> Vec<Vec<u8>> {
+        iter.map(|(k, _)| k.to_vec()).collect()
+    }
+
+    #[test]
+    fn test_start_and_end() {
+        let db = get_db();
+        let forward = keys(db.iter(IteratorMode::Start));
+        let mut reverse = keys(db.iter(IteratorMode::End));
+        assert_eq!(forward.len(), 7);
+        assert_eq!(forward[0], vec![1u8]);
+        reverse.reverse();
+        assert_eq!(forward, reverse);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_from() {
+        let db = get_db();
+        assert_eq!(keys(db.iter(IteratorMode::From(IVec::from(vec![3u8]), Direction::Forward))),
+                   vec![vec![3u8], vec![255u8], vec![255u8, 1u8]]);
+        assert_eq!(keys(db.iter(IteratorMode::From(IVec::from(vec![2u8, 1u8]), Direction::Reverse))),
+                   vec![vec![2u8, 0u8], vec![2u8], vec![1u8]]);
+    }
+
+    #[test]
+    fn test_range() {
+        let db = get_db();
+        let range = |from, to, direction| keys(db.iter(IteratorMode::Range(from, to, direction)));
+        let key = |k: &[u8]| IVec::from(k);
+
+        assert_eq!(range(Bound::Included(key(&[2])), Bound::Excluded(key(&[3])), Direction::Forward),
+                   vec![vec![2u8], vec![2u8, 0u8], vec![2u8, 255u8]]);
+        assert_eq!(range(Bound::Excluded(key(&[2])), Bound::Included(key(&[3])), Direction::Reverse),
+                   vec![vec![3u8], vec![2u8, 255u8], vec![2u8, 0u8]]);
+        assert!(range(Bound::Included(key(&[3])), Bound::Excluded(key(&[3])), Direction::Forward).is_empty());
+        assert!(range(Bound::Excluded(key(&[3])), Bound::Excluded(key(&[2])), Direction::Forward).is_empty());
+    }
+
+    #[test]
+    fn test_scan_prefix() {
+        let db = get_db();
+        assert_eq!(keys(db.scan_prefix(&[2u8])), vec![vec![2u8], vec![2u8, 0u8], vec![2u8, 255u8]]);
+        assert_eq!(keys(db.scan_prefix(&[255u8])), vec![vec![255u8], vec![255u8, 1u8]]);
+        assert_eq!(keys(db.scan_prefix(&[4u8])), Vec::<Vec<u8>>::new());
+        assert_eq!(keys(db.scan_prefix(&[])).len(), 7);
+    }
+}
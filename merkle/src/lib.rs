@@ -8,13 +8,22 @@ mod codec;
 mod  merkle_storage;
 mod database;
 mod db_iterator;
+mod persistent_db;
+mod commit_log;
+mod segment;
 mod ivec;
+mod cache;
 
 pub mod prelude {
     pub use crate::database::*;
+    pub use crate::persistent_db::*;
+    pub use crate::commit_log::*;
+    pub use crate::segment::DEFAULT_MAX_SEGMENT_SIZE;
     pub use crate::merkle_storage::*;
     pub use crate::db_iterator::*;
     pub use crate::codec::*;
+    pub use crate::schema::*;
     pub use crate::hash::*;
     pub use crate::ivec::IVec;
+    pub use crate::cache::CacheStats;
 }
@@ -45,19 +45,23 @@
 use std::hash::Hash;
 use serde::Deserialize;
 use serde::Serialize;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use im::OrdMap;
 use failure::Fail;
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::Instant;
 use crate::hash::HashType;
 use std::convert::TryInto;
+use std::io::{Read, Write};
 use sodiumoxide::crypto::generichash::State;
 use crate::codec::BincodeEncoded;
 use crate::schema::KeyValueSchema;
-use crate::database::{KeyValueStoreWithSchema, Batch, DB, DBStats};
+use crate::cache::{BoundedCache, CacheStats};
+use crate::database::{KeyValueStoreWithSchema, Batch, DBStats, IteratorMode};
 use crate::database::DBError;
 const HASH_LEN: usize = 32;
+const SNAPSHOT_MAGIC: &[u8; 8] = b"MRKLSNAP";
+const SNAPSHOT_VERSION: u32 = 1;
 
 pub type ContextKey = Vec<String>;
 pub type ContextValue = Vec<u8>;
@@ -77,9 +81,16 @@ struct Node {
 
 type Tree = OrdMap<String, Node>;
 
+/// Node found in the staging area, trees modified with deferred hashing have no hash yet
+enum StagedNode {
+    Stored(Node),
+    Modified,
+}
+
 #[derive(Debug, Hash, Clone, Serialize, Deserialize)]
 struct Commit {
-    parent_commit_hash: Option<EntryHash>,
+    // first parent is the commit this one was created on top of, others were merged into it
+    parent_commit_hashes: Vec<EntryHash>,
     root_hash: EntryHash,
     time: u64,
     author: String,
@@ -93,14 +104,54 @@ enum Entry {
     Commit(Commit),
 }
 
-pub type MerkleStorageKV = dyn KeyValueStoreWithSchema<MerkleStorage> + Sync + Send;
+/// Database able to store all data of `MerkleStorage`: entries and named references
+pub trait MerkleStorageDB: KeyValueStoreWithSchema<MerkleStorage> + KeyValueStoreWithSchema<MerkleRefs> {}
+
+impl<T> MerkleStorageDB for T where T: KeyValueStoreWithSchema<MerkleStorage> + KeyValueStoreWithSchema<MerkleRefs> {}
+
+pub type MerkleStorageKV = dyn MerkleStorageDB + Sync + Send;
+
+/// Commit identified either directly by hash or by a named reference
+#[derive(Debug, Clone, PartialEq)]
+pub enum CommitRef {
+    Hash(EntryHash),
+    Name(String),
+}
+
+impl From<EntryHash> for CommitRef {
+    fn from(hash: EntryHash) -> Self { CommitRef::Hash(hash) }
+}
+
+impl From<&EntryHash> for CommitRef {
+    fn from(hash: &EntryHash) -> Self { CommitRef::Hash(*hash) }
+}
+
+impl From<&str> for CommitRef {
+    fn from(name: &str) -> Self { CommitRef::Name(name.to_string()) }
+}
+
+impl From<String> for CommitRef {
+    fn from(name: String) -> Self { CommitRef::Name(name) }
+}
 
 pub struct MerkleStorage {
     current_stage_tree: Option<Tree>,
+    // trees modified since they were last hashed, by their path, see `with_deferred_hashing`
+    working_trees: BTreeMap<ContextKey, Tree>,
+    deferred_hashing: bool,
     db: Arc<RwLock<MerkleStorageKV>>,
+    // decoded entries read from db, see `with_entry_cache`
+    entry_cache: Mutex<BoundedCache<EntryHash, Entry>>,
     staged: HashMap<EntryHash, Entry>,
     last_commit: Option<Commit>,
+    // reference moved by `commit`, set when checked out by name
+    current_ref: Option<String>,
+    // commits merged into the staging area, parents of the next commit besides the last commit
+    merge_parents: Vec<EntryHash>,
     map_stats: MerkleMapStats,
+    prune_trigger: StagedPruneTrigger,
+    // elems or bytes of the staging area over which it is pruned, grows when pruning does not help
+    next_prune_at: u64,
     cumul_set_exec_time: f64,
     // divide this by the next field to get avg time spent in _set
     set_exec_times: u64,
@@ -114,6 +165,8 @@ pub enum MerkleError {
     SerializationError { error: bincode::Error },
     #[fail(display = "SledDB error: {:?}", error)]
     DBError { error : DBError},
+    #[fail(display = "IO error: {:?}", error)]
+    IOError { error: std::io::Error },
     /// Internal unrecoverable bugs that should never occur
     #[fail(display = "No root retrieved for this commit!")]
     CommitRootNotFound,
@@ -125,12 +178,24 @@ pub enum MerkleError {
     FoundUnexpectedStructure { sought: String, found: String },
     #[fail(display = "Entry not found! Hash={}", hash)]
     EntryNotFound { hash: String },
+    #[fail(display = "Invalid proof: {}", reason)]
+    InvalidProof { reason: String },
+    #[fail(display = "Reference {} was changed concurrently", name)]
+    RefConflict { name: String },
+    #[fail(display = "Unresolved merge conflict under key {:?}", key)]
+    MergeConflict { key: String },
+    #[fail(display = "Invalid snapshot: {}", reason)]
+    InvalidSnapshot { reason: String },
 
     /// Wrong user input errors
     #[fail(display = "No value under key {:?}.", key)]
     ValueNotFound { key: String },
     #[fail(display = "Cannot search for an empty key.")]
     KeyEmpty,
+    #[fail(display = "Reference {} not found.", name)]
+    RefNotFound { name: String },
+    #[fail(display = "Reference {} already exists.", name)]
+    RefAlreadyExists { name: String },
 }
 
 impl From<DBError> for MerkleError {
@@ -142,10 +207,73 @@ impl From<bincode::Error> for MerkleError {
     fn from(error: bincode::Error) -> Self { MerkleError::SerializationError { error } }
 }
 
-#[derive(Serialize, Debug, Clone, Copy)]
+impl From<std::io::Error> for MerkleError {
+    fn from(error: std::io::Error) -> Self { MerkleError::IOError { error } }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, Default)]
 pub struct MerkleMapStats {
     staged_area_elems: u64,
+    staged_area_bytes: u64,
     current_tree_elems: u64,
+    staged_prunes: u64,
+    staged_elems_pruned: u64,
+}
+
+/// When to remove staged entries no longer reachable from the staged root, see
+/// `MerkleStorage::prune_staged`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StagedPruneTrigger {
+    Never,
+    /// Prune when the staging area has more entries
+    Elems(u64),
+    /// Prune when serialized entries in the staging area take more bytes
+    Bytes(u64),
+}
+
+/// Change to the staging area, see `MerkleStorage::apply_changes`
+#[derive(Debug, Clone, PartialEq)]
+pub enum ContextChange {
+    Set { key: ContextKey, value: ContextValue },
+    Delete { key: ContextKey },
+    RemoveRecursively { key: ContextKey },
+    Copy { from_key: ContextKey, to_key: ContextKey },
+}
+
+/// Change of a single value between two contexts, see `MerkleStorage::diff`
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub enum DiffEntry {
+    Added { key: ContextKey, value: ContextValue },
+    Removed { key: ContextKey, value: ContextValue },
+    Modified { key: ContextKey, old_value: ContextValue, new_value: ContextValue },
+}
+
+/// Value changed in incompatible ways on both sides of a merge, see `MerkleStorage::merge`.
+/// A side holding a tree or no value under the key has `None`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MergeConflict {
+    pub key: ContextKey,
+    pub base: Option<ContextValue>,
+    pub ours: Option<ContextValue>,
+    pub theirs: Option<ContextValue>,
+}
+
+/// How to resolve a `MergeConflict`
+#[derive(Debug, Clone, PartialEq)]
+pub enum MergeResolution {
+    /// Keep whatever is under the key in the staging area
+    Ours,
+    /// Take whatever is under the key in the merged commit
+    Theirs,
+    /// Set the value, or remove it if `None`
+    Value(Option<ContextValue>),
+}
+
+/// Outcome of a garbage collection run
+#[derive(Serialize, Debug, Clone, Copy, Default)]
+pub struct MerkleGCStats {
+    pub entries_removed: u64,
+    pub bytes_removed: u64,
 }
 
 #[derive(Serialize, Debug, Clone, Copy)]
@@ -158,6 +286,7 @@ pub struct MerkleStorageStats {
     pub db_stats : DBStats,
     pub map_stats: MerkleMapStats,
     pub perf_stats: MerklePerfStats,
+    pub cache_stats: CacheStats,
 }
 
 impl BincodeEncoded for EntryHash {}
@@ -172,22 +301,74 @@ impl KeyValueSchema for MerkleStorage {
     }
 }
 
+/// Schema of named references to commits, stored in the same DB as `MerkleStorage` entries,
+/// in a separate column family
+pub struct MerkleRefs;
+
+impl KeyValueSchema for MerkleRefs {
+    type Key = String;
+    type Value = EntryHash;
+
+    #[inline]
+    fn name() -> &'static str {
+        "merkle_refs"
+    }
+}
+
 impl MerkleStorage {
-    pub fn new(db: Arc<RwLock<DB>>) -> Self {
+    pub fn new(db: Arc<RwLock<MerkleStorageKV>>) -> Self {
         MerkleStorage {
             db,
+            entry_cache: Mutex::new(BoundedCache::new(0, 0)),
             staged: HashMap::new(),
             current_stage_tree: None,
+            working_trees: BTreeMap::new(),
+            deferred_hashing: false,
             last_commit: None,
-            map_stats: MerkleMapStats { staged_area_elems: 0, current_tree_elems: 0 },
+            current_ref: None,
+            merge_parents: Vec::new(),
+            map_stats: MerkleMapStats::default(),
+            prune_trigger: StagedPruneTrigger::Never,
+            next_prune_at: 0,
             cumul_set_exec_time: 0.0,
             set_exec_times: 0,
             set_exec_times_to_discard: 20,
         }
     }
 
+    /// Defer hashing of the staging area: `set`, `delete`, `remove_recursively`, `copy`,
+    /// `copy_from`, `merge` and `apply_changes` only modify trees in memory, and reads are served
+    /// from the modified trees. They are hashed and staged once the staged root hash is needed, by
+    /// `commit`, `get_staged_root_hash`, `merge` or `gc`. Intermediate versions of trees are never
+    /// hashed nor staged.
+    pub fn with_deferred_hashing(mut self, deferred_hashing: bool) -> Self {
+        self.deferred_hashing = deferred_hashing;
+        self
+    }
+
+    /// Cache up to `max_entries` decoded entries read from db, taking approximately up to
+    /// `max_bytes` (as measured by their encoded size). Least recently used entries are evicted
+    /// first. The cache is disabled by default.
+    pub fn with_entry_cache(self, max_entries: usize, max_bytes: u64) -> Self {
+        *self.entry_cache.lock().unwrap() = BoundedCache::new(max_entries, max_bytes);
+        self
+    }
+
+    /// Get hash of the root of the staging area.
+    pub fn get_staged_root_hash(&mut self) -> Result<EntryHash, MerkleError> {
+        let root = self.get_staged_root()?;
+        Ok(self.hash_tree(&root))
+    }
+
     /// Get value. Staging area is checked first, then last (checked out) commit.
     pub fn get(&mut self, key: &ContextKey) -> Result<ContextValue, MerkleError> {
+        if !self.working_trees.is_empty() {
+            return match self.find_working_node(key)? {
+                Some(StagedNode::Stored(node)) if node.node_kind == NodeKind::Leaf => self.get_blob(&node.entry_hash),
+                Some(_) => Err(MerkleError::ValueIsNotABlob { key: self.key_to_string(key) }),
+                None => Err(MerkleError::ValueNotFound { key: self.key_to_string(key) }),
+            };
+        }
         let root = &self.get_staged_root()?;
         let root_hash = self.hash_tree(&root);
 
@@ -196,6 +377,9 @@ impl MerkleStorage {
 
     /// Get value. Staging area is checked first, then last (checked out) commit.
     pub fn get_by_prefix(&mut self, prefix: &ContextKey) -> Result<Option<Vec<(ContextKey, ContextValue)>>, MerkleError> {
+        if !self.working_trees.is_empty() {
+            return self.get_working_key_values_by_prefix(prefix);
+        }
         let root = self.get_staged_root()?;
         self._get_key_values_by_prefix(root, prefix)
     }
@@ -207,6 +391,73 @@ impl MerkleStorage {
         self.get_from_tree(&commit.root_hash, key)
     }
 
+    /// Check whether there is a value under `key`, without loading it. Staging area is checked
+    /// first, then last (checked out) commit.
+    pub fn mem(&mut self, key: &ContextKey) -> Result<bool, MerkleError> {
+        match self.find_staged_node(key)? {
+            Some(StagedNode::Stored(node)) => Ok(node.node_kind == NodeKind::Leaf),
+            _ => Ok(false),
+        }
+    }
+
+    /// Check whether there is a directory under `key`. Staging area is checked first, then last
+    /// (checked out) commit.
+    pub fn dir_mem(&mut self, key: &ContextKey) -> Result<bool, MerkleError> {
+        match self.find_staged_node(key)? {
+            Some(StagedNode::Stored(node)) => Ok(node.node_kind == NodeKind::NonLeaf),
+            Some(StagedNode::Modified) => Ok(true),
+            None => Ok(false),
+        }
+    }
+
+    /// Check whether there is a value under `key` in historical context identified by commit hash.
+    pub fn mem_history(&self, commit_hash: &EntryHash, key: &ContextKey) -> Result<bool, MerkleError> {
+        let root = self.get_tree(&self.get_commit(commit_hash)?.root_hash)?;
+        Ok(is_node_kind(&self.find_node(&root, key)?, NodeKind::Leaf))
+    }
+
+    /// Check whether there is a directory under `key` in historical context identified by commit hash.
+    pub fn dir_mem_history(&self, commit_hash: &EntryHash, key: &ContextKey) -> Result<bool, MerkleError> {
+        let root = self.get_tree(&self.get_commit(commit_hash)?.root_hash)?;
+        Ok(is_node_kind(&self.find_node(&root, key)?, NodeKind::NonLeaf))
+    }
+
+    /// Walk trees down to the node under `key`, blobs are never loaded
+    fn find_node(&self, root: &Tree, key: &ContextKey) -> Result<Option<Node>, MerkleError> {
+        let (first, rest) = key.split_first().ok_or(MerkleError::KeyEmpty)?;
+        let mut node = root.get(first).cloned();
+        for segment in rest {
+            node = self.get_child_node(node, segment)?;
+        }
+        Ok(node)
+    }
+
+    /// Build a proof of the value under `key` in historical context identified by commit hash,
+    /// or of its absence. See `MerkleProof::verify`.
+    pub fn get_proof(&self, commit_hash: &EntryHash, key: &ContextKey) -> Result<MerkleProof, MerkleError> {
+        let (file, path) = key.split_last().ok_or(MerkleError::KeyEmpty)?;
+        let commit = self.get_commit(commit_hash)?;
+        let mut trees = vec![self.get_tree(&commit.root_hash)?];
+        let mut value = None;
+
+        for segment in path {
+            let next_hash = match trees.last().unwrap().get(segment) {
+                Some(node) if node.node_kind == NodeKind::NonLeaf => node.entry_hash,
+                _ => break,
+            };
+            trees.push(self.get_tree(&next_hash)?);
+        }
+        if trees.len() == key.len() {
+            if let Some(node) = trees.last().unwrap().get(file) {
+                if node.node_kind == NodeKind::Leaf {
+                    value = Some(self.get_blob(&node.entry_hash)?);
+                }
+            }
+        }
+
+        Ok(MerkleProof { commit, trees, value })
+    }
+
     fn get_from_tree(&self, root_hash: &EntryHash, key: &ContextKey) -> Result<ContextValue, MerkleError> {
         let mut full_path = key.clone();
         let file = full_path.pop().ok_or(MerkleError::KeyEmpty)?;
@@ -286,20 +537,414 @@ impl MerkleStorage {
         }
     }
 
-    /// Flush the staging area and and move to work on a certain commit from history.
-    pub fn checkout(&mut self, context_hash: &EntryHash) -> Result<(), MerkleError> {
+    /// Get all changes of values under `prefix` between two historical contexts identified by
+    /// commit hashes, in key order. Subtrees with the same hash in both contexts are skipped.
+    pub fn diff(&self, commit_a: &EntryHash, commit_b: &EntryHash, prefix: &ContextKey) -> Result<Vec<DiffEntry>, MerkleError> {
+        let node_a = self.find_prefix_node(commit_a, prefix)?;
+        let node_b = self.find_prefix_node(commit_b, prefix)?;
+
+        let mut changes = Vec::new();
+        self.diff_nodes(prefix, node_a.as_ref(), node_b.as_ref(), &mut changes)?;
+        Ok(changes)
+    }
+
+    /// Node under `prefix` in historical context identified by commit hash, the root for an
+    /// empty prefix
+    fn find_prefix_node(&self, commit_hash: &EntryHash, prefix: &ContextKey) -> Result<Option<Node>, MerkleError> {
+        let root_hash = self.get_commit(commit_hash)?.root_hash;
+        if prefix.is_empty() {
+            Ok(Some(self.get_non_leaf(root_hash)))
+        } else {
+            self.find_node(&self.get_tree(&root_hash)?, prefix)
+        }
+    }
+
+    fn diff_nodes(&self, key: &ContextKey, node_a: Option<&Node>, node_b: Option<&Node>, changes: &mut Vec<DiffEntry>) -> Result<(), MerkleError> {
+        match (node_a, node_b) {
+            (Some(a), Some(b)) if a.entry_hash == b.entry_hash && a.node_kind == b.node_kind => (),
+            (Some(a), Some(b)) if a.node_kind == NodeKind::Leaf && b.node_kind == NodeKind::Leaf => {
+                changes.push(DiffEntry::Modified {
+                    key: key.clone(),
+                    old_value: self.get_blob(&a.entry_hash)?,
+                    new_value: self.get_blob(&b.entry_hash)?,
+                });
+            }
+            (Some(a), Some(b)) if a.node_kind == NodeKind::NonLeaf && b.node_kind == NodeKind::NonLeaf => {
+                self.diff_trees(key, &self.get_tree(&a.entry_hash)?, &self.get_tree(&b.entry_hash)?, changes)?;
+            }
+            (a, b) => {
+                if let Some(a) = a {
+                    for (key, value) in self.get_key_values_under_node(key, a)? {
+                        changes.push(DiffEntry::Removed { key, value });
+                    }
+                }
+                if let Some(b) = b {
+                    for (key, value) in self.get_key_values_under_node(key, b)? {
+                        changes.push(DiffEntry::Added { key, value });
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn diff_trees(&self, path: &ContextKey, tree_a: &Tree, tree_b: &Tree, changes: &mut Vec<DiffEntry>) -> Result<(), MerkleError> {
+        let mut iter_a = tree_a.iter().peekable();
+        let mut iter_b = tree_b.iter().peekable();
+
+        loop {
+            let (node_a, node_b) = match (iter_a.peek(), iter_b.peek()) {
+                (None, None) => return Ok(()),
+                (Some((key_a, _)), Some((key_b, _))) if key_a < key_b => (iter_a.next(), None),
+                (Some((key_a, _)), Some((key_b, _))) if key_a > key_b => (None, iter_b.next()),
+                (Some(_), None) => (iter_a.next(), None),
+                (None, Some(_)) => (None, iter_b.next()),
+                (Some(_), Some(_)) => (iter_a.next(), iter_b.next()),
+            };
+            let name = node_a.or(node_b).map(|(name, _)| name).unwrap();
+            let mut key = path.clone();
+            key.push(name.clone());
+            self.diff_nodes(&key, node_a.map(|(_, node)| node), node_b.map(|(_, node)| node), changes)?;
+        }
+    }
+
+    /// All values under `node` (the value of `node` itself if it is a blob) with their keys
+    fn get_key_values_under_node(&self, key: &ContextKey, node: &Node) -> Result<Vec<(ContextKey, ContextValue)>, MerkleError> {
+        let walk = TreeWalk {
+            storage: self,
+            stack: vec![(key.clone(), node.clone(), 0)],
+            max_depth: None,
+            keys_only: false,
+        };
+        walk.map(|item| item.map(|(key, value)| (key, value.unwrap_or_default()))).collect()
+    }
+
+    /// Lazily iterate over values under `prefix` in historical context identified by commit hash,
+    /// in key order. Trees are walked iteratively and loaded only when reached, so iteration can be
+    /// stopped early at no extra cost. See `TreeWalk` for depth limit and key-only mode.
+    pub fn walk(&self, commit_hash: &EntryHash, prefix: &ContextKey) -> Result<TreeWalk<'_>, MerkleError> {
+        let root = self.get_tree(&self.get_commit(commit_hash)?.root_hash)?;
+        let tree = if prefix.is_empty() {
+            Some(root)
+        } else {
+            match self.find_node(&root, prefix)? {
+                Some(node) if node.node_kind == NodeKind::NonLeaf => Some(self.get_tree(&node.entry_hash)?),
+                _ => None,
+            }
+        };
+
+        let mut walk = TreeWalk {
+            storage: self,
+            stack: Vec::new(),
+            max_depth: None,
+            keys_only: false,
+        };
+        if let Some(tree) = tree {
+            walk.push_children(prefix, &tree, 1);
+        }
+        Ok(walk)
+    }
+
+    /// Iterate over commits starting from `from_commit`, following first parents back to genesis
+    /// (or to the oldest commit retained by `gc`).
+    pub fn log(&self, from_commit: &EntryHash) -> CommitLog<'_> {
+        CommitLog {
+            storage: self,
+            next: Some(*from_commit),
+            is_first: true,
+            time_range: None,
+            author: None,
+        }
+    }
+
+    /// Get commits, starting from `commit_hash` back to genesis, in which the value under `key`
+    /// changed compared to their parent. `value` is `None` for commits which removed the value.
+    pub fn get_key_history(&self, commit_hash: &EntryHash, key: &ContextKey) -> Result<Vec<KeyHistoryEntry>, MerkleError> {
+        if key.is_empty() { return Err(MerkleError::KeyEmpty); }
+
+        let mut history = Vec::new();
+        let mut log = self.log(commit_hash).peekable();
+        while let Some(commit) = log.next() {
+            let commit = commit?;
+            let parent_root_hash = match log.peek() {
+                Some(Ok(parent)) => Some(parent.root_hash),
+                Some(Err(_)) => continue, // reported by the next iteration
+                // older history was garbage collected, so the change cannot be determined
+                None if !commit.parent_hashes.is_empty() => break,
+                None => None,
+            };
+
+            if let Some(blob_hash) = self.get_changed_blob_hash(&commit.root_hash, parent_root_hash, key)? {
+                let value = match blob_hash {
+                    Some(blob_hash) => Some(self.get_blob(&blob_hash)?),
+                    None => None,
+                };
+                history.push(KeyHistoryEntry { commit, value });
+            }
+        }
+        Ok(history)
+    }
+
+    /// Compare blobs under `key` in two trees, walking both paths in lockstep and stopping
+    /// as soon as they share a subtree. Returns `Some` with the blob hash from `root_hash` (`None`
+    /// if there is no value) if it differs from `parent_root_hash`.
+    fn get_changed_blob_hash(&self,
+                             root_hash: &EntryHash,
+                             parent_root_hash: Option<EntryHash>,
+                             key: &ContextKey,
+    ) -> Result<Option<Option<EntryHash>>, MerkleError> {
+        let mut node = Some(self.get_non_leaf(*root_hash));
+        let mut parent_node = parent_root_hash.map(|hash| self.get_non_leaf(hash));
+
+        for segment in key {
+            match (&node, &parent_node) {
+                (Some(a), Some(b)) if a.entry_hash == b.entry_hash => return Ok(None),
+                (None, None) => return Ok(None),
+                _ => (),
+            }
+            node = self.get_child_node(node, segment)?;
+            parent_node = self.get_child_node(parent_node, segment)?;
+        }
+
+        let blob_hash = |node: Option<Node>| node.filter(|n| n.node_kind == NodeKind::Leaf).map(|n| n.entry_hash);
+        let (blob, parent_blob) = (blob_hash(node), blob_hash(parent_node));
+        if blob == parent_blob {
+            Ok(None)
+        } else {
+            Ok(Some(blob))
+        }
+    }
+
+    fn get_child_node(&self, node: Option<Node>, name: &str) -> Result<Option<Node>, MerkleError> {
+        match node {
+            Some(node) if node.node_kind == NodeKind::NonLeaf => Ok(self.get_tree(&node.entry_hash)?.get(name).cloned()),
+            _ => Ok(None),
+        }
+    }
+
+    /// Export the context of a commit, i.e. the commit and all trees and blobs reachable from its
+    /// root (but not its parents), into `writer`. Returns the number of exported entries.
+    ///
+    /// Snapshot format:
+    /// ```no_compile
+    /// [magic: "MRKLSNAP"][version: u32][commit hash]
+    /// [entry_len: u32][entry]...  - children always precede trees, the commit is last
+    /// [0: u32][checksum: blake2b-256 of all preceding bytes]
+    /// ```
+    pub fn export_snapshot<W: Write>(&self, commit_hash: &EntryHash, writer: W) -> Result<u64, MerkleError> {
+        let commit = self.get_commit(commit_hash)?;
+        let mut writer = HashingWriter::new(writer);
+        writer.write_all(SNAPSHOT_MAGIC)?;
+        writer.write_all(&SNAPSHOT_VERSION.to_be_bytes())?;
+        writer.write_all(commit_hash)?;
+
+        // iterative post-order walk, trees are written once all their children were written
+        let mut entries_count = 0;
+        let mut visited: HashSet<EntryHash> = HashSet::new();
+        let mut stack: Vec<(Node, Option<Entry>)> = vec![(self.get_non_leaf(commit.root_hash), None)];
+        while let Some((node, loaded)) = stack.pop() {
+            if let Some(entry) = loaded {
+                write_snapshot_entry(&mut writer, &entry)?;
+                entries_count += 1;
+                continue;
+            }
+            if !visited.insert(node.entry_hash) {
+                continue;
+            }
+            let entry = self.get_entry(&node.entry_hash)?;
+            match &entry {
+                Entry::Tree(tree) => {
+                    let children: Vec<(Node, Option<Entry>)> = tree.values()
+                        .filter(|child| !visited.contains(&child.entry_hash))
+                        .map(|child| (child.clone(), None))
+                        .collect();
+                    stack.push((node, Some(entry)));
+                    stack.extend(children);
+                }
+                _ => {
+                    write_snapshot_entry(&mut writer, &entry)?;
+                    entries_count += 1;
+                }
+            }
+        }
+        write_snapshot_entry(&mut writer, &Entry::Commit(commit))?;
+        entries_count += 1;
+
+        writer.write_all(&0u32.to_be_bytes())?;
+        let checksum = writer.finalize();
+        writer.inner.write_all(&checksum)?;
+        writer.inner.flush()?;
+        Ok(entries_count)
+    }
+
+    /// Import a snapshot created by `export_snapshot`. Every entry is verified against its hash,
+    /// and trees against the presence of their children, before anything is written to the
+    /// database in a single batch. Returns hash of the imported commit.
+    pub fn import_snapshot<R: Read>(&mut self, reader: R) -> Result<EntryHash, MerkleError> {
+        let invalid = |reason: &str| MerkleError::InvalidSnapshot { reason: reason.to_string() };
+        let mut reader = HashingReader::new(reader);
+
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if magic != *SNAPSHOT_MAGIC {
+            return Err(invalid("not a snapshot"));
+        }
+        let mut version = [0u8; 4];
+        reader.read_exact(&mut version)?;
+        if u32::from_be_bytes(version) != SNAPSHOT_VERSION {
+            return Err(invalid("unsupported version"));
+        }
+        let mut commit_hash: EntryHash = [0; HASH_LEN];
+        reader.read_exact(&mut commit_hash)?;
+
+        let mut batch = Batch::default();
+        let mut imported: HashSet<EntryHash> = HashSet::new();
+        let mut commit: Option<Commit> = None;
+        loop {
+            let mut len = [0u8; 4];
+            reader.read_exact(&mut len)?;
+            let len = u32::from_be_bytes(len) as usize;
+            if len == 0 {
+                break;
+            }
+            if commit.is_some() {
+                return Err(invalid("entries after commit"));
+            }
+            // the length is not trusted before the checksum is verified, so the buffer only grows
+            // with the data actually read
+            let mut bytes = Vec::new();
+            reader.by_ref().take(len as u64).read_to_end(&mut bytes)?;
+            if bytes.len() != len {
+                return Err(invalid("truncated entry"));
+            }
+            let entry: Entry = bincode::deserialize(&bytes)?;
+
+            let children_present = match &entry {
+                Entry::Blob(_) => true,
+                Entry::Tree(tree) => tree.values().all(|child| imported.contains(&child.entry_hash)),
+                Entry::Commit(c) => imported.contains(&c.root_hash),
+            };
+            if !children_present {
+                return Err(invalid("entry precedes its children"));
+            }
+            let hash = self.hash_entry(&entry);
+            KeyValueStoreWithSchema::<MerkleStorage>::put_batch(&*self.db.read().unwrap(), &mut batch, &hash, &bytes)?;
+            imported.insert(hash);
+            if let Entry::Commit(c) = entry {
+                if hash != commit_hash {
+                    return Err(invalid("commit does not match its hash"));
+                }
+                commit = Some(c);
+            }
+        }
+
+        let checksum = reader.finalize();
+        let mut expected_checksum = [0u8; HASH_LEN];
+        reader.inner.read_exact(&mut expected_checksum)?;
+        if checksum != expected_checksum {
+            return Err(invalid("checksum mismatch"));
+        }
+        if commit.is_none() {
+            return Err(invalid("missing commit"));
+        }
+
+        KeyValueStoreWithSchema::<MerkleStorage>::write_batch(&mut *self.db.write().unwrap(), batch)?;
+        Ok(commit_hash)
+    }
+
+    /// Flush the staging area and and move to work on a certain commit from history. When checked
+    /// out by reference name, following commits move the reference.
+    pub fn checkout<R: Into<CommitRef>>(&mut self, commit_ref: R) -> Result<(), MerkleError> {
+        let commit_ref = commit_ref.into();
+        let context_hash = self.resolve_ref(&commit_ref)?;
         let commit = self.get_commit(&context_hash)?;
+        self.working_trees = BTreeMap::new();
         self.current_stage_tree = Some(self.get_tree(&commit.root_hash)?);
         self.map_stats.current_tree_elems = self.current_stage_tree.as_ref().unwrap().len() as u64;
         self.last_commit = Some(commit);
-        self.staged = HashMap::new();
-        self.map_stats.staged_area_elems = 0;
+        self.current_ref = match commit_ref {
+            CommitRef::Name(name) => Some(name),
+            CommitRef::Hash(_) => None,
+        };
+        self.merge_parents = Vec::new();
+        self.clear_staging_area();
+        Ok(())
+    }
+
+    /// Get hash of the commit identified by `commit_ref`.
+    pub fn resolve_ref(&self, commit_ref: &CommitRef) -> Result<EntryHash, MerkleError> {
+        match commit_ref {
+            CommitRef::Hash(hash) => Ok(*hash),
+            CommitRef::Name(name) => self.get_ref(name)?.ok_or_else(|| MerkleError::RefNotFound { name: name.clone() }),
+        }
+    }
+
+    /// Get commit hash the reference `name` points to.
+    pub fn get_ref(&self, name: &str) -> Result<Option<EntryHash>, MerkleError> {
+        let db = self.db.read().unwrap();
+        Ok(KeyValueStoreWithSchema::<MerkleRefs>::get(&*db, &name.to_string())?)
+    }
+
+    /// Create a new reference `name` pointing to an existing commit. Fails if the reference
+    /// already exists.
+    pub fn create_ref(&mut self, name: &str, commit_hash: &EntryHash) -> Result<(), MerkleError> {
+        self.update_ref(name, None, commit_hash)
+            .map_err(|err| match err {
+                MerkleError::RefConflict { name } => MerkleError::RefAlreadyExists { name },
+                err => err,
+            })
+    }
+
+    /// Atomically point reference `name` to `new_hash`, if it currently points to `expected_hash`
+    /// (`None` meaning the reference must not exist yet), otherwise fail with `RefConflict`.
+    pub fn update_ref(&mut self, name: &str, expected_hash: Option<&EntryHash>, new_hash: &EntryHash) -> Result<(), MerkleError> {
+        self.get_commit(new_hash)?;
+
+        let mut db = self.db.write().unwrap();
+        let key = name.to_string();
+        let current_hash = KeyValueStoreWithSchema::<MerkleRefs>::get(&*db, &key)?;
+        if current_hash.as_ref() != expected_hash {
+            return Err(MerkleError::RefConflict { name: name.to_string() });
+        }
+        KeyValueStoreWithSchema::<MerkleRefs>::merge(&mut *db, &key, new_hash)?;
         Ok(())
     }
 
+    /// Delete reference `name`. Commits it pointed to are not affected.
+    pub fn delete_ref(&mut self, name: &str) -> Result<(), MerkleError> {
+        let mut db = self.db.write().unwrap();
+        let key = name.to_string();
+        if !KeyValueStoreWithSchema::<MerkleRefs>::contains(&*db, &key)? {
+            return Err(MerkleError::RefNotFound { name: name.to_string() });
+        }
+        KeyValueStoreWithSchema::<MerkleRefs>::delete(&mut *db, &key)?;
+        if self.current_ref.as_deref() == Some(name) {
+            self.current_ref = None;
+        }
+        Ok(())
+    }
+
+    /// List all references with commit hashes they point to, ordered by name.
+    pub fn list_refs(&self) -> Result<Vec<(String, EntryHash)>, MerkleError> {
+        let db = self.db.read().unwrap();
+        let mut refs = Vec::new();
+        for (name, hash) in KeyValueStoreWithSchema::<MerkleRefs>::iterator(&*db, IteratorMode::Start)? {
+            refs.push((name.map_err(DBError::from)?, hash.map_err(DBError::from)?));
+        }
+        Ok(refs)
+    }
+
+    /// Name of the reference moved by `commit`, if any
+    pub fn get_current_ref(&self) -> Option<&str> {
+        self.current_ref.as_deref()
+    }
+
     /// Take the current changes in the staging area, create a commit and persist all changes
     /// to database under the new commit. Return last commit if there are no changes, that is
     /// empty commits are not allowed.
+    ///
+    /// When committing on top of a reference, fails with `RefConflict` if the reference no longer
+    /// points to the last commit. The staging area and last commit are left untouched then.
     pub fn commit(&mut self,
                   time: u64,
                   author: String,
@@ -309,30 +954,199 @@ impl MerkleStorage {
         let staged_root_hash = self.hash_tree(&staged_root);
         let parent_commit_hash = self.last_commit.as_ref()
             .map_or(None, |c| Some(self.hash_commit(&c)));
+        let mut parent_commit_hashes: Vec<EntryHash> = parent_commit_hash.into_iter().collect();
+        parent_commit_hashes.extend(self.merge_parents.iter().cloned());
 
         let new_commit = Commit {
             root_hash: staged_root_hash,
-            parent_commit_hash,
+            parent_commit_hashes,
             time,
             author,
             message,
         };
         let entry = Entry::Commit(new_commit.clone());
+        let new_commit_hash = self.hash_commit(&new_commit);
+
+        if let Some(name) = &self.current_ref {
+            if self.get_ref(name)? != parent_commit_hash {
+                return Err(MerkleError::RefConflict { name: name.clone() });
+            }
+        }
 
-        self.put_to_staging_area(&self.hash_commit(&new_commit), entry.clone());
+        self.put_to_staging_area(&new_commit_hash, entry.clone());
         self.persist_staged_entry_to_db(&entry)?;
-        self.staged = HashMap::new();
-        self.map_stats.staged_area_elems = 0;
-        self.last_commit = Some(new_commit.clone());
-        Ok(self.hash_commit(&new_commit))
+        // entries persisted by a commit which then loses the race for the reference are just
+        // unreachable and left to gc
+        if let Some(name) = self.current_ref.clone() {
+            self.update_ref(&name, parent_commit_hash.as_ref(), &new_commit_hash)?;
+        }
+        self.clear_staging_area();
+        self.merge_parents.clear();
+        self.last_commit = Some(new_commit);
+        Ok(new_commit_hash)
+    }
+
+    /// Three-way merge commit `other` into the staging area, using the common ancestor of the
+    /// last commit and `other` as base. Values changed differently on both sides, or changed on
+    /// one side and turned into a tree on the other, are resolved by `resolver`. The next commit
+    /// will have both the last commit and `other` as parents.
+    pub fn merge<R>(&mut self, other: &EntryHash, mut resolver: R) -> Result<(), MerkleError>
+        where R: FnMut(&MergeConflict) -> Result<MergeResolution, MerkleError>
+    {
+        let head = self.get_last_commit_hash().ok_or(MerkleError::MissingAncestorCommit)?;
+        let other_root_hash = self.get_commit(other)?.root_hash;
+        let base = self.find_merge_base(&head, other)?;
+        if base == Some(*other) {
+            // already merged
+            return Ok(());
+        }
+        for parent in self.merge_parents.clone() {
+            if self.find_merge_base(&parent, other)? == Some(*other) {
+                // already merged into the staging area, but not committed yet
+                return Ok(());
+            }
+        }
+
+        let base_root = match base {
+            Some(base) => Some(self.get_non_leaf(self.get_commit(&base)?.root_hash)),
+            None => None,
+        };
+        let staged_root = self.get_staged_root()?;
+        let ours_root = Some(self.get_non_leaf(self.hash_tree(&staged_root)));
+        let theirs_root = Some(self.get_non_leaf(other_root_hash));
+
+        let merged_root = match self.merge_nodes(&mut Vec::new(), base_root, ours_root, theirs_root, &mut resolver)? {
+            Some(node) => self.get_tree(&node.entry_hash)?,
+            None => Tree::new(),
+        };
+        if self.deferred_hashing {
+            // the merged root is hashed along with the following changes
+            self.working_trees.insert(Vec::new(), merged_root);
+        } else {
+            self.put_to_staging_area(&self.hash_tree(&merged_root), Entry::Tree(merged_root.clone()));
+            self.map_stats.current_tree_elems = merged_root.len() as u64;
+            self.current_stage_tree = Some(merged_root);
+        }
+        self.merge_parents.push(*other);
+        self.prune_staged_if_needed();
+        Ok(())
+    }
+
+    /// Find the nearest common ancestor of two commits, following all parents. Both histories
+    /// are walked breadth-first, alternating level by level, until one of them reaches a commit
+    /// already seen by the other.
+    pub fn find_merge_base(&self, commit_a: &EntryHash, commit_b: &EntryHash) -> Result<Option<EntryHash>, MerkleError> {
+        let mut levels = [vec![*commit_a], vec![*commit_b]];
+        let mut visited = [HashSet::new(), HashSet::new()];
+        let mut side = 0;
+        while !levels[0].is_empty() || !levels[1].is_empty() {
+            for hash in std::mem::take(&mut levels[side]) {
+                if visited[1 - side].contains(&hash) {
+                    return Ok(Some(hash));
+                }
+                if !visited[side].insert(hash) {
+                    continue;
+                }
+                match self.get_commit(&hash) {
+                    Ok(commit) => levels[side].extend(commit.parent_commit_hashes),
+                    Err(err) if Self::is_missing_entry(&err) => (),
+                    Err(err) => return Err(err),
+                }
+            }
+            side = 1 - side;
+        }
+        Ok(None)
+    }
+
+    /// Merge nodes under `key`, staging all newly created trees and blobs.
+    fn merge_nodes<R>(&mut self,
+                      key: &mut ContextKey,
+                      base: Option<Node>,
+                      ours: Option<Node>,
+                      theirs: Option<Node>,
+                      resolver: &mut R,
+    ) -> Result<Option<Node>, MerkleError>
+        where R: FnMut(&MergeConflict) -> Result<MergeResolution, MerkleError>
+    {
+        let same = |a: &Option<Node>, b: &Option<Node>| match (a, b) {
+            (Some(a), Some(b)) => a.entry_hash == b.entry_hash && a.node_kind == b.node_kind,
+            (None, None) => true,
+            _ => false,
+        };
+        if same(&ours, &theirs) || same(&base, &theirs) {
+            return Ok(ours);
+        }
+        if same(&base, &ours) {
+            return Ok(theirs);
+        }
+
+        let is_leaf = |node: &Option<Node>| matches!(node, Some(n) if n.node_kind == NodeKind::Leaf);
+        if !is_leaf(&ours) && !is_leaf(&theirs) {
+            let base_tree = self.get_tree_or_empty(&base)?;
+            let ours_tree = self.get_tree_or_empty(&ours)?;
+            let theirs_tree = self.get_tree_or_empty(&theirs)?;
+            let names: BTreeSet<String> = base_tree.keys().chain(ours_tree.keys()).chain(theirs_tree.keys()).cloned().collect();
+
+            let mut merged = Tree::new();
+            for name in names {
+                key.push(name.clone());
+                let node = self.merge_nodes(key, base_tree.get(&name).cloned(), ours_tree.get(&name).cloned(), theirs_tree.get(&name).cloned(), resolver)?;
+                key.pop();
+                if let Some(node) = node {
+                    merged.insert(name, node);
+                }
+            }
+            if merged.is_empty() {
+                return Ok(None);
+            }
+            let merged_hash = self.hash_tree(&merged);
+            self.put_to_staging_area(&merged_hash, Entry::Tree(merged));
+            return Ok(Some(self.get_non_leaf(merged_hash)));
+        }
+
+        let conflict = MergeConflict {
+            key: key.clone(),
+            base: self.get_leaf_value(&base)?,
+            ours: self.get_leaf_value(&ours)?,
+            theirs: self.get_leaf_value(&theirs)?,
+        };
+        match resolver(&conflict)? {
+            MergeResolution::Ours => Ok(ours),
+            MergeResolution::Theirs => Ok(theirs),
+            MergeResolution::Value(None) => Ok(None),
+            MergeResolution::Value(Some(value)) => {
+                let blob_hash = self.hash_blob(&value);
+                self.put_to_staging_area(&blob_hash, Entry::Blob(value));
+                Ok(Some(Node { entry_hash: blob_hash, node_kind: NodeKind::Leaf }))
+            }
+        }
+    }
+
+    fn get_tree_or_empty(&self, node: &Option<Node>) -> Result<Tree, MerkleError> {
+        match node {
+            Some(node) if node.node_kind == NodeKind::NonLeaf => self.get_tree(&node.entry_hash),
+            _ => Ok(Tree::new()),
+        }
+    }
+
+    fn get_leaf_value(&self, node: &Option<Node>) -> Result<Option<ContextValue>, MerkleError> {
+        match node {
+            Some(node) if node.node_kind == NodeKind::Leaf => Ok(Some(self.get_blob(&node.entry_hash)?)),
+            _ => Ok(None),
+        }
     }
 
     /// Set key/val to the staging area.
     pub fn set(&mut self, key: &ContextKey, value: &ContextValue) -> Result<(), MerkleError> {
+        if key.is_empty() { return Err(MerkleError::KeyEmpty); }
+        if self.deferred_hashing {
+            return self.apply_change_deferred(ContextChange::Set { key: key.clone(), value: value.clone() });
+        }
         let root = self.get_staged_root()?;
         let new_root_hash = &self._set(&root, key, value)?;
         self.current_stage_tree = Some(self.get_tree(new_root_hash)?);
         self.map_stats.current_tree_elems = self.current_stage_tree.as_ref().unwrap().len() as u64;
+        self.prune_staged_if_needed();
         Ok(())
     }
 
@@ -350,12 +1164,286 @@ impl MerkleStorage {
         rv
     }
 
-    /// Delete an item from the staging area.
+    /// Set many key/values to the staging area, see `apply_changes`.
+    pub fn set_many(&mut self, key_values: &[(ContextKey, ContextValue)]) -> Result<(), MerkleError> {
+        self.apply_changes(key_values.iter().map(|(key, value)| ContextChange::Set { key: key.clone(), value: value.clone() }))
+    }
+
+    /// Apply changes to the staging area, in order, with the same semantics as `set`, `delete`,
+    /// `remove_recursively` and `copy`. Unlike those, every tree touched by the changes is hashed
+    /// and staged only once, after all changes were applied.
+    ///
+    /// On error, no change is applied, unless hashing is deferred: then changes preceding the
+    /// failed one stay applied.
+    pub fn apply_changes<I: IntoIterator<Item = ContextChange>>(&mut self, changes: I) -> Result<(), MerkleError> {
+        let mut trees = self.take_working_trees()?;
+        let result = changes.into_iter().try_for_each(|change| self.apply_change(&mut trees, change));
+        if self.deferred_hashing {
+            self.working_trees = trees;
+            self.prune_staged_if_needed();
+            return result;
+        }
+        result?;
+        self.working_trees = trees;
+        self.hash_working_trees()?;
+        self.prune_staged_if_needed();
+        Ok(())
+    }
+
+    /// Apply change to modified trees (by their path), ancestors of a modified tree are always
+    /// present
+    fn apply_change(&mut self, trees: &mut BTreeMap<ContextKey, Tree>, change: ContextChange) -> Result<(), MerkleError> {
+        match change {
+            ContextChange::Set { key, value } => {
+                if key.is_empty() { return Err(MerkleError::KeyEmpty); }
+                let blob_hash = self.hash_blob(&value);
+                self.put_to_staging_area(&blob_hash, Entry::Blob(value));
+                self.replace_node(trees, &key, Some(Node { entry_hash: blob_hash, node_kind: NodeKind::Leaf }))?;
+            }
+            ContextChange::Delete { key } => {
+                // modified trees are not hashed yet, their parents may still point to a blob
+                if !key.is_empty() && !trees.contains_key(&key)
+                    && is_node_kind(&self.find_changed_node(trees, &key)?, NodeKind::Leaf) {
+                    self.replace_node(trees, &key, None)?;
+                }
+            }
+            ContextChange::RemoveRecursively { key } => {
+                if !key.is_empty() && (trees.contains_key(&key) || self.find_changed_node(trees, &key)?.is_some()) {
+                    self.replace_node(trees, &key, None)?;
+                }
+            }
+            ContextChange::Copy { from_key, to_key } => {
+                if to_key.is_empty() { return Err(MerkleError::KeyEmpty); }
+                // source must be hashed before it can be shared
+                self.hash_changed_trees(trees, &from_key)?;
+                let source = if from_key.is_empty() {
+                    Some(self.get_non_leaf(self.hash_tree(&trees[&from_key])))
+                } else {
+                    self.find_changed_node(trees, &from_key)?
+                };
+                if let Some(source) = source {
+                    self.replace_node(trees, &to_key, Some(source))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn apply_change_deferred(&mut self, change: ContextChange) -> Result<(), MerkleError> {
+        self.apply_changes(std::iter::once(change))
+    }
+
+    /// Take modified trees out of the storage, starting with the staged root if there are none
+    fn take_working_trees(&mut self) -> Result<BTreeMap<ContextKey, Tree>, MerkleError> {
+        if self.working_trees.is_empty() {
+            let mut trees = BTreeMap::new();
+            trees.insert(Vec::new(), self.get_staged_root()?);
+            Ok(trees)
+        } else {
+            Ok(std::mem::take(&mut self.working_trees))
+        }
+    }
+
+    /// Find node under `key` in the staging area without hashing modified trees
+    fn find_staged_node(&mut self, key: &ContextKey) -> Result<Option<StagedNode>, MerkleError> {
+        if self.working_trees.is_empty() {
+            let root = self.get_staged_root()?;
+            return Ok(self.find_node(&root, key)?.map(StagedNode::Stored));
+        }
+        self.find_working_node(key)
+    }
+
+    /// Find node under `key` in modified trees. Trees left empty are absent, as they will be
+    /// removed once hashed.
+    fn find_working_node(&self, key: &ContextKey) -> Result<Option<StagedNode>, MerkleError> {
+        if key.is_empty() { return Err(MerkleError::KeyEmpty); }
+        if self.working_trees.contains_key(key) {
+            if self.working_children(key).is_empty() {
+                return Ok(None);
+            }
+            return Ok(Some(StagedNode::Modified));
+        }
+        Ok(self.find_changed_node(&self.working_trees, key)?.map(StagedNode::Stored))
+    }
+
+    /// Children of the modified tree under `path` as they will be once hashed, `None` for modified
+    /// subtrees. Subtrees left empty are left out.
+    fn working_children(&self, path: &[String]) -> Vec<(String, Option<Node>)> {
+        let mut children: BTreeMap<String, Option<Node>> = self.working_trees[path].iter()
+            .map(|(name, node)| (name.clone(), Some(node.clone())))
+            .collect();
+        let subtrees = self.working_trees.range(path.to_vec()..)
+            .take_while(|(subtree, _)| subtree.starts_with(path))
+            .filter(|(subtree, _)| subtree.len() == path.len() + 1);
+        for (subtree, _) in subtrees {
+            let name = subtree.last().unwrap().clone();
+            if self.working_children(subtree).is_empty() {
+                children.remove(&name);
+            } else {
+                children.insert(name, None);
+            }
+        }
+        children.into_iter().collect()
+    }
+
+    fn get_working_key_values_by_prefix(&self, prefix: &ContextKey) -> Result<Option<Vec<(ContextKey, ContextValue)>>, MerkleError> {
+        let node = if prefix.is_empty() {
+            Some(StagedNode::Modified)
+        } else {
+            self.find_working_node(prefix)?
+        };
+
+        let mut keyvalues = Vec::new();
+        match node {
+            Some(StagedNode::Modified) => self.collect_working_key_values(prefix, &mut keyvalues)?,
+            Some(StagedNode::Stored(node)) if node.node_kind == NodeKind::NonLeaf => {
+                keyvalues = self.get_key_values_under_node(prefix, &node)?;
+            }
+            _ => (),
+        }
+
+        if keyvalues.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(keyvalues))
+        }
+    }
+
+    fn collect_working_key_values(&self, path: &ContextKey, keyvalues: &mut Vec<(ContextKey, ContextValue)>) -> Result<(), MerkleError> {
+        for (name, node) in self.working_children(path) {
+            let mut key = path.clone();
+            key.push(name);
+            match node {
+                Some(node) => keyvalues.extend(self.get_key_values_under_node(&key, &node)?),
+                None => self.collect_working_key_values(&key, keyvalues)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Hash and stage all modified trees, the staged root is then up to date
+    fn hash_working_trees(&mut self) -> Result<(), MerkleError> {
+        if self.working_trees.is_empty() {
+            return Ok(());
+        }
+        let mut trees = std::mem::take(&mut self.working_trees);
+        self.hash_changed_trees(&mut trees, &[])?;
+        self.current_stage_tree = trees.remove(&Vec::new());
+        self.map_stats.current_tree_elems = self.current_stage_tree.as_ref().unwrap().len() as u64;
+        Ok(())
+    }
+
+    /// Find node under `key`, looking into modified trees first
+    fn find_changed_node(&self, trees: &BTreeMap<ContextKey, Tree>, key: &[String]) -> Result<Option<Node>, MerkleError> {
+        let mut depth = key.len() - 1;
+        while !trees.contains_key(&key[..depth]) {
+            depth -= 1;
+        }
+        let mut node = trees[&key[..depth]].get(&key[depth]).cloned();
+        for segment in &key[depth + 1..] {
+            node = self.get_child_node(node, segment)?;
+        }
+        Ok(node)
+    }
+
+    /// Put `node` under `key` (or remove it, if `None`), loading trees on the path as needed.
+    /// Blobs on the path are replaced by trees, like in `compute_new_root_with_change`.
+    fn replace_node(&self, trees: &mut BTreeMap<ContextKey, Tree>, key: &[String], node: Option<Node>) -> Result<(), MerkleError> {
+        let (name, path) = key.split_last().unwrap();
+        for depth in 1..=path.len() {
+            if !trees.contains_key(&path[..depth]) {
+                let tree = match trees[&path[..depth - 1]].get(&path[depth - 1]) {
+                    Some(node) if node.node_kind == NodeKind::NonLeaf => self.get_tree(&node.entry_hash)?,
+                    _ => Tree::new(),
+                };
+                trees.insert(path[..depth].to_vec(), tree);
+            }
+        }
+
+        // modified trees under `key` are replaced as well
+        let replaced: Vec<ContextKey> = trees.range(key.to_vec()..)
+            .take_while(|(path, _)| path.starts_with(key))
+            .map(|(path, _)| path.clone())
+            .collect();
+        for path in replaced {
+            trees.remove(&path);
+        }
+
+        let tree = trees.get_mut(path).unwrap();
+        match node {
+            Some(node) => tree.insert(name.clone(), node),
+            None => tree.remove(name),
+        };
+        Ok(())
+    }
+
+    /// Hash and stage modified trees under `key`, deepest first, and remove them from `trees`,
+    /// updating their parents. Empty trees are removed from their parents, the root is always kept.
+    fn hash_changed_trees(&mut self, trees: &mut BTreeMap<ContextKey, Tree>, key: &[String]) -> Result<(), MerkleError> {
+        let mut paths: Vec<ContextKey> = trees.range(key.to_vec()..)
+            .take_while(|(path, _)| path.starts_with(key))
+            .map(|(path, _)| path.clone())
+            .collect();
+        paths.sort_by_key(|path| std::cmp::Reverse(path.len()));
+
+        for path in paths {
+            let (name, parent) = match path.split_last() {
+                Some(split) => split,
+                None => {
+                    // root stays in `trees`, so that it can be modified further
+                    let root = &trees[&path];
+                    self.put_to_staging_area(&self.hash_tree(root), Entry::Tree(root.clone()));
+                    continue;
+                }
+            };
+            let tree = trees.remove(&path).unwrap();
+            let node = if tree.is_empty() {
+                None
+            } else {
+                let tree_hash = self.hash_tree(&tree);
+                self.put_to_staging_area(&tree_hash, Entry::Tree(tree));
+                Some(self.get_non_leaf(tree_hash))
+            };
+            let parent_tree = trees.get_mut(parent).unwrap();
+            match node {
+                Some(node) => parent_tree.insert(name.clone(), node),
+                None => parent_tree.remove(name),
+            };
+        }
+        Ok(())
+    }
+
+    /// Delete the value under `key`. Directories are left untouched, see `remove_recursively`.
+    /// Trees left empty are removed as well.
     pub fn delete(&mut self, key: &ContextKey) -> Result<(), MerkleError> {
+        if self.deferred_hashing {
+            return self.apply_change_deferred(ContextChange::Delete { key: key.clone() });
+        }
         let root = self.get_staged_root()?;
+        if key.is_empty() || !is_node_kind(&self.find_node(&root, key)?, NodeKind::Leaf) {
+            return Ok(());
+        }
         let new_root_hash = &self._delete(&root, key)?;
         self.current_stage_tree = Some(self.get_tree(new_root_hash)?);
         self.map_stats.current_tree_elems = self.current_stage_tree.as_ref().unwrap().len() as u64;
+        self.prune_staged_if_needed();
+        Ok(())
+    }
+
+    /// Remove whatever is under `key`, a value or a whole directory. Trees left empty are removed
+    /// as well.
+    pub fn remove_recursively(&mut self, key: &ContextKey) -> Result<(), MerkleError> {
+        if self.deferred_hashing {
+            return self.apply_change_deferred(ContextChange::RemoveRecursively { key: key.clone() });
+        }
+        let root = self.get_staged_root()?;
+        if key.is_empty() || self.find_node(&root, key)?.is_none() {
+            return Ok(());
+        }
+        let new_root_hash = &self._delete(&root, key)?;
+        self.current_stage_tree = Some(self.get_tree(new_root_hash)?);
+        self.map_stats.current_tree_elems = self.current_stage_tree.as_ref().unwrap().len() as u64;
+        self.prune_staged_if_needed();
         Ok(())
     }
 
@@ -365,23 +1453,58 @@ impl MerkleStorage {
         self.compute_new_root_with_change(root, &key, None)
     }
 
-    /// Copy subtree under a new path.
-    /// TODO Consider copying values!
+    /// Copy value or subtree under a new path. Nothing is copied if there is nothing under `from_key`.
     pub fn copy(&mut self, from_key: &ContextKey, to_key: &ContextKey) -> Result<(), MerkleError> {
+        if self.deferred_hashing {
+            return self.apply_change_deferred(ContextChange::Copy { from_key: from_key.clone(), to_key: to_key.clone() });
+        }
+        let root = self.get_staged_root()?;
+        let source = if from_key.is_empty() {
+            Some(self.get_non_leaf(self.hash_tree(&root)))
+        } else {
+            self.find_node(&root, from_key)?
+        };
+        self.graft(&root, source, to_key)
+    }
+
+    /// Copy value or subtree from historical context identified by commit hash under a new path.
+    /// Entries are shared by hash, nothing is re-hashed. Nothing is copied if there is nothing under
+    /// `from_key`.
+    pub fn copy_from(&mut self, commit_hash: &EntryHash, from_key: &ContextKey, to_key: &ContextKey) -> Result<(), MerkleError> {
+        let source_root_hash = self.get_commit(commit_hash)?.root_hash;
+        let source = if from_key.is_empty() {
+            Some(self.get_non_leaf(source_root_hash))
+        } else {
+            self.find_node(&self.get_tree(&source_root_hash)?, from_key)?
+        };
+        if self.deferred_hashing {
+            if to_key.is_empty() { return Err(MerkleError::KeyEmpty); }
+            let source = match source {
+                Some(source) => source,
+                None => return Ok(()),
+            };
+            let mut trees = self.take_working_trees()?;
+            let result = self.replace_node(&mut trees, to_key, Some(source));
+            self.working_trees = trees;
+            self.prune_staged_if_needed();
+            return result;
+        }
         let root = self.get_staged_root()?;
-        let new_root_hash = &self._copy(&root, from_key, to_key)?;
+        self.graft(&root, source, to_key)
+    }
+
+    fn graft(&mut self, root: &Tree, source: Option<Node>, to_key: &ContextKey) -> Result<(), MerkleError> {
+        let source = match source {
+            Some(source) => source,
+            None => return Ok(()),
+        };
+        let new_root_hash = &self.compute_new_root_with_change(root, to_key, Some(source))?;
         self.current_stage_tree = Some(self.get_tree(new_root_hash)?);
         self.map_stats.current_tree_elems = self.current_stage_tree.as_ref().unwrap().len() as u64;
+        self.prune_staged_if_needed();
         Ok(())
     }
 
-    fn _copy(&mut self, root: &Tree, from_key: &ContextKey, to_key: &ContextKey) -> Result<EntryHash, MerkleError> {
-        let source_tree = self.find_tree(root, &from_key)?;
-        let source_tree_hash = self.hash_tree(&source_tree);
-        Ok(self.compute_new_root_with_change(
-            &root, &to_key, Some(self.get_non_leaf(source_tree_hash)))?)
-    }
-
     /// Get a new tree with `new_entry_hash` put under given `key`.
     ///
     /// # Arguments
@@ -395,8 +1518,16 @@ impl MerkleStorage {
                                     new_node: Option<Node>,
     ) -> Result<EntryHash, MerkleError> {
         if key.is_empty() {
-            return Ok(new_node.unwrap_or_else(
-                || self.get_non_leaf(self.hash_tree(root))).entry_hash);
+            return match new_node {
+                Some(new_node) => Ok(new_node.entry_hash),
+                None => {
+                    // the whole tree was removed
+                    let tree = Tree::new();
+                    let tree_hash = self.hash_tree(&tree);
+                    self.put_to_staging_area(&tree_hash, Entry::Tree(tree));
+                    Ok(tree_hash)
+                }
+            };
         }
 
         let last = key.last().unwrap();
@@ -449,6 +1580,7 @@ impl MerkleStorage {
 
     /// Get latest staged tree. If it's empty, init genesis  and return genesis root.
     fn get_staged_root(&mut self) -> Result<Tree, MerkleError> {
+        self.hash_working_trees()?;
         match &self.current_stage_tree {
             None => {
                 let tree = Tree::new();
@@ -464,10 +1596,97 @@ impl MerkleStorage {
     }
 
     fn put_to_staging_area(&mut self, key: &EntryHash, value: Entry) {
-        self.staged.insert(*key, value);
+        let size = encoded_entry_size(&value);
+        if self.staged.insert(*key, value).is_none() {
+            self.map_stats.staged_area_bytes += size;
+        }
         self.map_stats.staged_area_elems = self.staged.len() as u64;
     }
 
+    fn clear_staging_area(&mut self) {
+        self.staged = HashMap::new();
+        self.map_stats.staged_area_elems = 0;
+        self.map_stats.staged_area_bytes = 0;
+        if let StagedPruneTrigger::Elems(limit) | StagedPruneTrigger::Bytes(limit) = self.prune_trigger {
+            self.next_prune_at = limit;
+        }
+    }
+
+    /// Remove staged entries no longer reachable from the staged root (or from trees modified
+    /// with deferred hashing), i.e. superseded versions of trees and blobs. Returns the number
+    /// of removed entries.
+    pub fn prune_staged(&mut self) -> u64 {
+        let mut to_visit: Vec<EntryHash> = Vec::new();
+        if let Some(root) = &self.current_stage_tree {
+            to_visit.push(self.hash_tree(root));
+        }
+        for tree in self.working_trees.values() {
+            to_visit.extend(tree.values().map(|node| node.entry_hash));
+        }
+
+        // only staged entries are walked, the rest is already in DB
+        let mut reachable: HashSet<EntryHash> = HashSet::new();
+        while let Some(hash) = to_visit.pop() {
+            if reachable.contains(&hash) {
+                continue;
+            }
+            if let Some(entry) = self.staged.get(&hash) {
+                reachable.insert(hash);
+                if let Entry::Tree(tree) = entry {
+                    to_visit.extend(tree.values().map(|node| node.entry_hash));
+                }
+            }
+        }
+
+        let elems_before = self.staged.len() as u64;
+        let mut bytes_removed = 0;
+        self.staged.retain(|hash, entry| {
+            let keep = reachable.contains(hash);
+            if !keep {
+                bytes_removed += encoded_entry_size(entry);
+            }
+            keep
+        });
+        let removed = elems_before - self.staged.len() as u64;
+        self.map_stats.staged_area_elems = self.staged.len() as u64;
+        self.map_stats.staged_area_bytes -= bytes_removed;
+        self.map_stats.staged_prunes += 1;
+        self.map_stats.staged_elems_pruned += removed;
+        removed
+    }
+
+    /// Set when `prune_staged` runs automatically, after a change of the staging area.
+    pub fn with_staged_prune_trigger(mut self, trigger: StagedPruneTrigger) -> Self {
+        self.prune_trigger = trigger;
+        if let StagedPruneTrigger::Elems(limit) | StagedPruneTrigger::Bytes(limit) = trigger {
+            self.next_prune_at = limit;
+        }
+        self
+    }
+
+    fn prune_staged_if_needed(&mut self) {
+        let (limit, size) = match self.staged_size() {
+            Some(limit_and_size) => limit_and_size,
+            None => return,
+        };
+        if size <= self.next_prune_at {
+            return;
+        }
+        self.prune_staged();
+        // when most entries are still reachable, pruning again soon would not free much
+        let (_, size) = self.staged_size().unwrap();
+        self.next_prune_at = std::cmp::max(limit, size * 2);
+    }
+
+    /// Limit and size of the staging area, as measured by the prune trigger
+    fn staged_size(&self) -> Option<(u64, u64)> {
+        match self.prune_trigger {
+            StagedPruneTrigger::Never => None,
+            StagedPruneTrigger::Elems(limit) => Some((limit, self.map_stats.staged_area_elems)),
+            StagedPruneTrigger::Bytes(limit) => Some((limit, self.map_stats.staged_area_bytes)),
+        }
+    }
+
     /// Persists an entry and its descendants from staged area to database on disk.
     fn persist_staged_entry_to_db(&mut self, entry: &Entry) -> Result<(), MerkleError> {
         let mut batch = Batch::default(); // batch containing DB key values to persist
@@ -476,7 +1695,7 @@ impl MerkleStorage {
         self.get_entries_recursively(entry, &mut batch)?;
 
         // atomically write all entries in one batch to DB
-        self.db.write().unwrap().write_batch(batch)?;
+        KeyValueStoreWithSchema::<MerkleStorage>::write_batch(&mut *self.db.write().unwrap(), batch)?;
 
         Ok(())
     }
@@ -488,7 +1707,7 @@ impl MerkleStorage {
 
         let k = &self.hash_entry(entry);
         let v = bincode::serialize(entry)?;
-        self.db.write().unwrap().put_batch(batch,k,&v);
+        KeyValueStoreWithSchema::<MerkleStorage>::put_batch(&*self.db.write().unwrap(), batch, k, &v)?;
         match entry {
             Entry::Blob(_) => Ok(()),
             Entry::Tree(tree) => {
@@ -515,6 +1734,89 @@ impl MerkleStorage {
         }
     }
 
+    /// Remove from the database every entry, which is no longer reachable from the retained
+    /// commits. The last commit, commits pointed to by references, commits merged into the staging
+    /// area and the staging area itself are always retained.
+    ///
+    /// # Arguments
+    ///
+    /// * `commits_to_keep` - hashes of commits which, together with their trees, must survive
+    pub fn gc(&mut self, commits_to_keep: &[EntryHash]) -> Result<MerkleGCStats, MerkleError> {
+        self.hash_working_trees()?;
+        let mut roots: Vec<EntryHash> = commits_to_keep.to_vec();
+        roots.extend(self.get_last_commit_hash());
+        roots.extend(self.list_refs()?.into_iter().map(|(_, hash)| hash));
+        roots.extend(self.merge_parents.iter().cloned());
+
+        // mark
+        let mut reachable: HashSet<EntryHash> = HashSet::new();
+        let mut nodes_to_visit = Vec::new();
+        for commit_hash in &roots {
+            let commit = self.get_commit(commit_hash)?;
+            reachable.insert(*commit_hash);
+            nodes_to_visit.push(self.get_non_leaf(commit.root_hash));
+        }
+        if let Some(staged_root) = &self.current_stage_tree {
+            nodes_to_visit.extend(staged_root.values().cloned());
+        }
+        self.collect_subtrees(nodes_to_visit, &mut reachable)?;
+
+        // sweep
+        let mut stats = MerkleGCStats::default();
+        let mut batch = Batch::default();
+        let mut db = self.db.write().unwrap();
+        let mut cache = self.entry_cache.lock().unwrap();
+        for (hash, value) in KeyValueStoreWithSchema::<MerkleStorage>::iterator(&*db, IteratorMode::Start)? {
+            let hash = hash.map_err(DBError::from)?;
+            if reachable.contains(&hash) {
+                continue;
+            }
+            let value = value.map_err(DBError::from)?;
+            cache.remove(&hash);
+            stats.entries_removed += 1;
+            stats.bytes_removed += (hash.len() + value.len()) as u64;
+            KeyValueStoreWithSchema::<MerkleStorage>::delete_batch(&*db, &mut batch, &hash)?;
+        }
+        KeyValueStoreWithSchema::<MerkleStorage>::write_batch(&mut *db, batch)?;
+        Ok(stats)
+    }
+
+    /// Keep only the last `levels` commits of the current branch, see `gc`.
+    pub fn gc_keep_last(&mut self, levels: usize) -> Result<MerkleGCStats, MerkleError> {
+        let mut commits_to_keep = Vec::with_capacity(levels);
+        let mut next = self.get_last_commit_hash();
+        while let Some(commit_hash) = next {
+            if commits_to_keep.len() >= levels {
+                break;
+            }
+            commits_to_keep.push(commit_hash);
+            next = self.get_commit(&commit_hash)?.parent_commit_hashes.first().cloned();
+        }
+        self.gc(&commits_to_keep)
+    }
+
+    /// Add entries of `nodes` and all entries below them to `visited`. Blobs are recognized by
+    /// their node kind and never loaded. Fails if a tree is missing, as nothing may be swept then.
+    fn collect_subtrees(&self, nodes: Vec<Node>, visited: &mut HashSet<EntryHash>) -> Result<(), MerkleError> {
+        let mut stack = nodes;
+        while let Some(node) = stack.pop() {
+            if !visited.insert(node.entry_hash) {
+                continue;
+            }
+            if node.node_kind == NodeKind::Leaf {
+                continue;
+            }
+            if let Entry::Tree(tree) = self.get_entry(&node.entry_hash)? {
+                stack.extend(tree.values().cloned());
+            }
+        }
+        Ok(())
+    }
+
+    fn is_missing_entry(err: &MerkleError) -> bool {
+        matches!(err, MerkleError::EntryNotFound { .. })
+    }
+
     fn hash_entry(&self, entry: &Entry) -> EntryHash {
         match entry {
             Entry::Commit(commit) => self.hash_commit(&commit),
@@ -523,129 +1825,425 @@ impl MerkleStorage {
         }
     }
 
-    fn hash_commit(&self, commit: &Commit) -> EntryHash {
-        let mut hasher = State::new(HASH_LEN, None).unwrap();
+    fn hash_commit(&self, commit: &Commit) -> EntryHash {
+        hash_commit(commit)
+    }
+
+    fn hash_tree(&self, tree: &Tree) -> EntryHash {
+        hash_tree(tree)
+    }
+
+    fn hash_blob(&self, blob: &ContextValue) -> EntryHash {
+        hash_blob(blob)
+    }
+
+    fn get_tree(&self, hash: &EntryHash) -> Result<Tree, MerkleError> {
+        match self.get_entry(hash)? {
+            Entry::Tree(tree) => Ok(tree),
+            Entry::Blob(_) => Err(MerkleError::FoundUnexpectedStructure {
+                sought: "tree".to_string(),
+                found: "blob".to_string(),
+            }),
+            Entry::Commit { .. } => Err(MerkleError::FoundUnexpectedStructure {
+                sought: "tree".to_string(),
+                found: "commit".to_string(),
+            }),
+        }
+    }
+
+    fn get_blob(&self, hash: &EntryHash) -> Result<ContextValue, MerkleError> {
+        match self.get_entry(hash)? {
+            Entry::Blob(blob) => Ok(blob),
+            Entry::Tree(_) => Err(MerkleError::FoundUnexpectedStructure {
+                sought: "blob".to_string(),
+                found: "tree".to_string(),
+            }),
+            Entry::Commit { .. } => Err(MerkleError::FoundUnexpectedStructure {
+                sought: "blob".to_string(),
+                found: "commit".to_string(),
+            }),
+        }
+    }
+
+    fn get_commit(&self, hash: &EntryHash) -> Result<Commit, MerkleError> {
+        match self.get_entry(hash)? {
+            Entry::Commit(commit) => Ok(commit),
+            Entry::Tree(_) => Err(MerkleError::FoundUnexpectedStructure {
+                sought: "commit".to_string(),
+                found: "tree".to_string(),
+            }),
+            Entry::Blob(_) => Err(MerkleError::FoundUnexpectedStructure {
+                sought: "commit".to_string(),
+                found: "blob".to_string(),
+            }),
+        }
+    }
+
+    fn get_entry(&self, hash: &EntryHash) -> Result<Entry, MerkleError> {
+        match self.staged.get(hash) {
+            None => {
+                let mut cache = self.entry_cache.lock().unwrap();
+                if !cache.is_enabled() {
+                    drop(cache);
+                    return self.get_entry_from_db(hash);
+                }
+                if let Some(entry) = cache.get(hash) {
+                    return Ok(entry);
+                }
+                drop(cache);
+
+                let entry = self.get_entry_from_db(hash)?;
+                self.entry_cache.lock().unwrap().put(*hash, entry.clone(), encoded_entry_size(&entry));
+                Ok(entry)
+            }
+            Some(entry) => Ok(entry.clone()),
+        }
+    }
+
+    fn get_entry_from_db(&self, hash: &EntryHash) -> Result<Entry, MerkleError> {
+        let entry_bytes = KeyValueStoreWithSchema::<MerkleStorage>::get(&*self.db.read().unwrap(), hash)?;
+        match entry_bytes {
+            None => Err(MerkleError::EntryNotFound { hash: HashType::ContextHash.bytes_to_string(hash) }),
+            Some(entry_bytes) => {
+                Ok(bincode::deserialize(entry_bytes.as_ref())?)
+            }
+        }
+    }
+
+    fn get_non_leaf(&self, hash: EntryHash) -> Node {
+        Node { node_kind: NodeKind::NonLeaf, entry_hash: hash }
+    }
+
+    fn key_to_string(&self, key: &ContextKey) -> String {
+        key.join("/")
+    }
+
+    fn string_to_key(&self, string: &str) -> ContextKey {
+        string.split('/').map(str::to_string).collect()
+    }
+
+    pub fn get_last_commit_hash(&self) -> Option<EntryHash> {
+        match &self.last_commit {
+            Some(c) => Some(self.hash_commit(&c)),
+            None => None
+        }
+    }
+
+    pub fn get_merkle_stats(&self) -> Result<MerkleStorageStats, MerkleError> {
+        let mut avg_set_exec_time_ns: f64 = 0.0;
+        if self.set_exec_times > self.set_exec_times_to_discard {
+            avg_set_exec_time_ns = self.cumul_set_exec_time / ((self.set_exec_times - self.set_exec_times_to_discard) as f64);
+        }
+        let perf = MerklePerfStats { avg_set_exec_time_ns: avg_set_exec_time_ns };
+        let db_reader = self.db.read().unwrap();
+        let db_stats = KeyValueStoreWithSchema::<MerkleStorage>::get_mem_use_stats(&*db_reader).unwrap_or_default();
+        let cache_stats = self.entry_cache.lock().unwrap().stats();
+        Ok(MerkleStorageStats { db_stats, map_stats: self.map_stats, perf_stats: perf, cache_stats })
+    }
+}
+
+/// Proof that a value is (or is not) stored under a key in a specific commit.
+///
+/// Contains the commit and all trees along the key's path starting from the commit's root tree,
+/// so that a verifier can recompute every hash up to the commit hash. The path ends where the key
+/// is proven to be absent, or at the tree holding the value.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MerkleProof {
+    commit: Commit,
+    trees: Vec<Tree>,
+    value: Option<ContextValue>,
+}
+
+impl MerkleProof {
+    /// Check the proof against a trusted commit hash. Returns the value under `key`, or `None`
+    /// if the proof shows that there is no value under `key`.
+    pub fn verify(&self, commit_hash: &EntryHash, key: &ContextKey) -> Result<Option<ContextValue>, MerkleError> {
+        if key.is_empty() { return Err(MerkleError::KeyEmpty); }
+        if hash_commit(&self.commit) != *commit_hash {
+            return Err(MerkleError::InvalidProof { reason: "commit hash mismatch".to_string() });
+        }
+
+        let mut expected_hash = self.commit.root_hash;
+        for (depth, tree) in self.trees.iter().enumerate() {
+            if hash_tree(tree) != expected_hash {
+                return Err(MerkleError::InvalidProof { reason: format!("tree hash mismatch at depth {}", depth) });
+            }
+            let is_last_tree = depth + 1 == self.trees.len();
+            let is_last_segment = depth + 1 == key.len();
+
+            match tree.get(&key[depth]) {
+                Some(node) if is_last_segment && node.node_kind == NodeKind::Leaf => {
+                    return match &self.value {
+                        Some(value) if hash_blob(value) == node.entry_hash && is_last_tree => Ok(Some(value.clone())),
+                        _ => Err(MerkleError::InvalidProof { reason: "value does not match its hash".to_string() }),
+                    };
+                }
+                Some(node) if !is_last_segment && node.node_kind == NodeKind::NonLeaf => {
+                    expected_hash = node.entry_hash;
+                }
+                // key is missing or the path is interrupted by a value or ends in a tree
+                _ if is_last_tree && self.value.is_none() => return Ok(None),
+                _ => return Err(MerkleError::InvalidProof { reason: "proof continues past the end of the path".to_string() }),
+            }
+        }
+
+        Err(MerkleError::InvalidProof { reason: "proof ends before the end of the path".to_string() })
+    }
+}
+
+fn write_snapshot_entry<W: Write>(writer: &mut W, entry: &Entry) -> Result<(), MerkleError> {
+    let bytes = bincode::serialize(entry)?;
+    writer.write_all(&(bytes.len() as u32).to_be_bytes())?;
+    writer.write_all(&bytes)?;
+    Ok(())
+}
+
+/// Writer computing checksum of all written bytes
+struct HashingWriter<W: Write> {
+    inner: W,
+    hasher: State,
+}
+
+impl<W: Write> HashingWriter<W> {
+    fn new(inner: W) -> Self {
+        HashingWriter { inner, hasher: State::new(HASH_LEN, None).unwrap() }
+    }
+
+    fn finalize(&mut self) -> EntryHash {
+        let hasher = std::mem::replace(&mut self.hasher, State::new(HASH_LEN, None).unwrap());
+        hasher.finalize().unwrap().as_ref().try_into().expect("EntryHash conversion error")
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]).expect("hasher");
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Reader computing checksum of all read bytes
+struct HashingReader<R: Read> {
+    inner: R,
+    hasher: State,
+}
+
+impl<R: Read> HashingReader<R> {
+    fn new(inner: R) -> Self {
+        HashingReader { inner, hasher: State::new(HASH_LEN, None).unwrap() }
+    }
+
+    fn finalize(&mut self) -> EntryHash {
+        let hasher = std::mem::replace(&mut self.hasher, State::new(HASH_LEN, None).unwrap());
+        hasher.finalize().unwrap().as_ref().try_into().expect("EntryHash conversion error")
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]).expect("hasher");
+        Ok(read)
+    }
+}
+
+fn hash_commit(commit: &Commit) -> EntryHash {
+    let mut hasher = State::new(HASH_LEN, None).unwrap();
+    hasher.update(&(HASH_LEN as u64).to_be_bytes()).expect("hasher");
+    hasher.update(&commit.root_hash).expect("hasher");
+
+    // Irmin hashes parents sorted
+    let mut parents = commit.parent_commit_hashes.clone();
+    parents.sort_unstable();
+    hasher.update(&(parents.len() as u64).to_be_bytes()).expect("hasher");
+    for parent in &parents {
+        hasher.update(&(parent.len() as u64).to_be_bytes()).expect("hasher");
+        hasher.update(parent).expect("hasher");
+    }
+    hasher.update(&(commit.time as u64).to_be_bytes()).expect("hasher");
+    hasher.update(&(commit.author.len() as u64).to_be_bytes()).expect("hasher");
+    hasher.update(&commit.author.clone().into_bytes()).expect("hasher");
+    hasher.update(&(commit.message.len() as u64).to_be_bytes()).expect("hasher");
+    hasher.update(&commit.message.clone().into_bytes()).expect("hasher");
+
+    hasher.finalize().unwrap().as_ref().try_into().expect("EntryHash conversion error")
+}
+
+fn hash_tree(tree: &Tree) -> EntryHash {
+    let mut hasher = State::new(HASH_LEN, None).unwrap();
+
+    hasher.update(&(tree.len() as u64).to_be_bytes()).expect("hasher");
+    tree.iter().for_each(|(k, v)| {
+        hasher.update(&encode_irmin_node_kind(&v.node_kind)).expect("hasher");
+        hasher.update(&[k.len() as u8]).expect("hasher");
+        hasher.update(&k.clone().into_bytes()).expect("hasher");
         hasher.update(&(HASH_LEN as u64).to_be_bytes()).expect("hasher");
-        hasher.update(&commit.root_hash).expect("hasher");
+        hasher.update(&v.entry_hash).expect("hasher");
+    });
 
-        if commit.parent_commit_hash.is_none() {
-            hasher.update(&(0 as u64).to_be_bytes()).expect("hasher");
-        } else {
-            hasher.update(&(1 as u64).to_be_bytes()).expect("hasher"); // # of parents; we support only 1
-            hasher.update(&(commit.parent_commit_hash.unwrap().len() as u64).to_be_bytes()).expect("hasher");
-            hasher.update(&commit.parent_commit_hash.unwrap()).expect("hasher");
-        }
-        hasher.update(&(commit.time as u64).to_be_bytes()).expect("hasher");
-        hasher.update(&(commit.author.len() as u64).to_be_bytes()).expect("hasher");
-        hasher.update(&commit.author.clone().into_bytes()).expect("hasher");
-        hasher.update(&(commit.message.len() as u64).to_be_bytes()).expect("hasher");
-        hasher.update(&commit.message.clone().into_bytes()).expect("hasher");
+    hasher.finalize().unwrap().as_ref().try_into().expect("EntryHash conversion error")
+}
 
-        hasher.finalize().unwrap().as_ref().try_into().expect("EntryHash conversion error")
-    }
+fn hash_blob(blob: &ContextValue) -> EntryHash {
+    let mut hasher = State::new(HASH_LEN, None).unwrap();
+    hasher.update(&(blob.len() as u64).to_be_bytes()).expect("Failed to update hasher state");
+    hasher.update(blob).expect("Failed to update hasher state");
 
-    fn hash_tree(&self, tree: &Tree) -> EntryHash {
-        let mut hasher = State::new(HASH_LEN, None).unwrap();
-
-        hasher.update(&(tree.len() as u64).to_be_bytes()).expect("hasher");
-        tree.iter().for_each(|(k, v)| {
-            hasher.update(&self.encode_irmin_node_kind(&v.node_kind)).expect("hasher");
-            hasher.update(&[k.len() as u8]).expect("hasher");
-            hasher.update(&k.clone().into_bytes()).expect("hasher");
-            hasher.update(&(HASH_LEN as u64).to_be_bytes()).expect("hasher");
-            hasher.update(&v.entry_hash).expect("hasher");
-        });
+    hasher.finalize().unwrap().as_ref().try_into().expect("EntryHash conversion error")
+}
 
-        hasher.finalize().unwrap().as_ref().try_into().expect("EntryHash conversion error")
-    }
+fn encoded_entry_size(entry: &Entry) -> u64 {
+    bincode::serialized_size(entry).unwrap_or(0)
+}
 
-    fn hash_blob(&self, blob: &ContextValue) -> EntryHash {
-        let mut hasher = State::new(HASH_LEN, None).unwrap();
-        hasher.update(&(blob.len() as u64).to_be_bytes()).expect("Failed to update hasher state");
-        hasher.update(blob).expect("Failed to update hasher state");
+fn is_node_kind(node: &Option<Node>, kind: NodeKind) -> bool {
+    matches!(node, Some(node) if node.node_kind == kind)
+}
 
-        hasher.finalize().unwrap().as_ref().try_into().expect("EntryHash conversion error")
+fn encode_irmin_node_kind(kind: &NodeKind) -> Vec<u8> {
+    match kind {
+        NodeKind::NonLeaf => vec![0, 0, 0, 0, 0, 0, 0, 0],
+        NodeKind::Leaf => vec![255, 0, 0, 0, 0, 0, 0, 0],
     }
+}
 
-    fn encode_irmin_node_kind(&self, kind: &NodeKind) -> Vec<u8> {
-        match kind {
-            NodeKind::NonLeaf => vec![0, 0, 0, 0, 0, 0, 0, 0],
-            NodeKind::Leaf => vec![255, 0, 0, 0, 0, 0, 0, 0],
-        }
-    }
+/// Commit metadata yielded by `CommitLog`
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct CommitInfo {
+    pub hash: EntryHash,
+    pub parent_hashes: Vec<EntryHash>,
+    pub root_hash: EntryHash,
+    pub time: u64,
+    pub author: String,
+    pub message: String,
+}
 
+/// Commit in which a value changed, see `MerkleStorage::get_key_history`
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct KeyHistoryEntry {
+    pub commit: CommitInfo,
+    pub value: Option<ContextValue>,
+}
 
-    fn get_tree(&self, hash: &EntryHash) -> Result<Tree, MerkleError> {
-        match self.get_entry(hash)? {
-            Entry::Tree(tree) => Ok(tree),
-            Entry::Blob(_) => Err(MerkleError::FoundUnexpectedStructure {
-                sought: "tree".to_string(),
-                found: "blob".to_string(),
-            }),
-            Entry::Commit { .. } => Err(MerkleError::FoundUnexpectedStructure {
-                sought: "tree".to_string(),
-                found: "commit".to_string(),
-            }),
-        }
+/// Iterator over the history of a commit, see `MerkleStorage::log`
+pub struct CommitLog<'a> {
+    storage: &'a MerkleStorage,
+    next: Option<EntryHash>,
+    is_first: bool,
+    time_range: Option<(u64, u64)>,
+    author: Option<String>,
+}
+
+impl<'a> CommitLog<'a> {
+    /// Only yield commits with time between `from` and `to` (inclusive).
+    pub fn time_range(mut self, from: u64, to: u64) -> Self {
+        self.time_range = Some((from, to));
+        self
     }
 
-    fn get_commit(&self, hash: &EntryHash) -> Result<Commit, MerkleError> {
-        match self.get_entry(hash)? {
-            Entry::Commit(commit) => Ok(commit),
-            Entry::Tree(_) => Err(MerkleError::FoundUnexpectedStructure {
-                sought: "commit".to_string(),
-                found: "tree".to_string(),
-            }),
-            Entry::Blob(_) => Err(MerkleError::FoundUnexpectedStructure {
-                sought: "commit".to_string(),
-                found: "blob".to_string(),
-            }),
-        }
+    /// Only yield commits made by `author`.
+    pub fn author(mut self, author: &str) -> Self {
+        self.author = Some(author.to_string());
+        self
     }
 
-    fn get_entry(&self, hash: &EntryHash) -> Result<Entry, MerkleError> {
-        match self.staged.get(hash) {
-            None => {
-                let entry_bytes = self.db.read().unwrap().get(hash)?;
-                match entry_bytes {
-                    None => Err(MerkleError::EntryNotFound { hash: HashType::ContextHash.bytes_to_string(hash) }),
-                    Some(entry_bytes) => {
-                        Ok(bincode::deserialize(entry_bytes.as_ref())?)
-                    }
-                }
+    fn matches(&self, commit: &Commit) -> bool {
+        let time_matches = match self.time_range {
+            Some((from, to)) => from <= commit.time && commit.time <= to,
+            None => true,
+        };
+        let author_matches = match &self.author {
+            Some(author) => *author == commit.author,
+            None => true,
+        };
+        time_matches && author_matches
+    }
+}
+
+impl<'a> Iterator for CommitLog<'a> {
+    type Item = Result<CommitInfo, MerkleError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(hash) = self.next.take() {
+            let commit = match self.storage.get_commit(&hash) {
+                Ok(commit) => commit,
+                // parents are missing when older history was garbage collected
+                Err(err) if !self.is_first && MerkleStorage::is_missing_entry(&err) => return None,
+                Err(err) => return Some(Err(err)),
+            };
+            self.is_first = false;
+            self.next = commit.parent_commit_hashes.first().cloned();
+
+            if self.matches(&commit) {
+                return Some(Ok(CommitInfo {
+                    hash,
+                    parent_hashes: commit.parent_commit_hashes,
+                    root_hash: commit.root_hash,
+                    time: commit.time,
+                    author: commit.author,
+                    message: commit.message,
+                }));
             }
-            Some(entry) => Ok(entry.clone()),
         }
+        None
     }
+}
 
-    fn get_non_leaf(&self, hash: EntryHash) -> Node {
-        Node { node_kind: NodeKind::NonLeaf, entry_hash: hash }
-    }
+/// Iterator over values of a subtree, see `MerkleStorage::walk`
+pub struct TreeWalk<'a> {
+    storage: &'a MerkleStorage,
+    /// nodes left to visit with their keys and depth below prefix, next one on top
+    stack: Vec<(ContextKey, Node, usize)>,
+    max_depth: Option<usize>,
+    keys_only: bool,
+}
 
-    fn key_to_string(&self, key: &ContextKey) -> String {
-        key.join("/")
+impl<'a> TreeWalk<'a> {
+    /// Only yield values at most `depth` segments below prefix, deeper trees are not loaded.
+    pub fn max_depth(mut self, depth: usize) -> Self {
+        self.max_depth = Some(depth);
+        self.stack.retain(|(_, _, node_depth)| *node_depth <= depth);
+        self
     }
 
-    fn string_to_key(&self, string: &str) -> ContextKey {
-        string.split('/').map(str::to_string).collect()
+    /// Yield keys only (with `None` values), blobs are not loaded.
+    pub fn keys_only(mut self) -> Self {
+        self.keys_only = true;
+        self
     }
 
-    pub fn get_last_commit_hash(&self) -> Option<EntryHash> {
-        match &self.last_commit {
-            Some(c) => Some(self.hash_commit(&c)),
-            None => None
+    fn push_children(&mut self, key: &[String], tree: &Tree, depth: usize) {
+        // reversed, so that the smallest key is popped first
+        for (name, node) in tree.iter().rev() {
+            let mut child_key = key.to_vec();
+            child_key.push(name.clone());
+            self.stack.push((child_key, node.clone(), depth));
         }
     }
+}
 
-    pub fn get_merkle_stats(&self) -> Result<MerkleStorageStats, MerkleError> {
-        let mut avg_set_exec_time_ns: f64 = 0.0;
-        if self.set_exec_times > self.set_exec_times_to_discard {
-            avg_set_exec_time_ns = self.cumul_set_exec_time / ((self.set_exec_times - self.set_exec_times_to_discard) as f64);
+impl<'a> Iterator for TreeWalk<'a> {
+    type Item = Result<(ContextKey, Option<ContextValue>), MerkleError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((key, node, depth)) = self.stack.pop() {
+            match node.node_kind {
+                NodeKind::Leaf if self.keys_only => return Some(Ok((key, None))),
+                NodeKind::Leaf => return Some(self.storage.get_blob(&node.entry_hash).map(|value| (key, Some(value)))),
+                // children would be too deep, so the tree is not even loaded
+                NodeKind::NonLeaf if matches!(self.max_depth, Some(max_depth) if depth >= max_depth) => (),
+                NodeKind::NonLeaf => match self.storage.get_tree(&node.entry_hash) {
+                    Ok(tree) => self.push_children(&key, &tree, depth + 1),
+                    Err(err) => return Some(Err(err)),
+                },
+            }
         }
-        let perf = MerklePerfStats { avg_set_exec_time_ns: avg_set_exec_time_ns };
-        let db_reader = self.db.read().unwrap();
-        let db_stats = db_reader.get_mem_use_stats().unwrap_or(DBStats{ db_size: 0, keys: 0 });
-        Ok(MerkleStorageStats { db_stats, map_stats: self.map_stats, perf_stats: perf })
+        None
     }
 }
 
@@ -655,12 +2253,15 @@ mod tests {
     use super::*;
     use serial_test::serial;
     use crate::database::{DB};
+    use crate::persistent_db::PersistentDB;
 
     /*
     * Tests need to run sequentially, otherwise they will try to open RocksDB at the same time.
     */
     fn get_storage() -> MerkleStorage { MerkleStorage::new(Arc::new(RwLock::new(DB::new()))) }
 
+    fn key(path: &str) -> ContextKey { path.split('/').map(str::to_string).collect() }
+
 
     #[test]
     #[serial]
@@ -842,13 +2443,13 @@ mod tests {
             commit2 = storage.commit(0, "".to_string(), "".to_string()).unwrap();
         }
 
-        storage.checkout(&commit1);
+        storage.checkout(commit1);
         assert_eq!(storage.get(&key_abc).unwrap(), vec![1u8]);
         assert_eq!(storage.get(&key_abx).unwrap(), vec![2u8]);
         // this set be wiped by checkout
         storage.set(key_abc, &vec![8u8]).unwrap();
 
-        storage.checkout(&commit2);
+        storage.checkout(commit2);
         assert_eq!(storage.get(&key_abc).unwrap(), vec![3u8]);
         assert_eq!(storage.get(&key_abx).unwrap(), vec![4u8]);
     }
@@ -909,12 +2510,14 @@ mod tests {
     #[test]
     #[serial]
     fn test_persistence_over_reopens() {
-        { clean_db(); }
+        let db_path = std::env::temp_dir().join("merkle_storage_tests").join("persistence_over_reopens");
+        let _ = std::fs::remove_dir_all(&db_path);
 
         let key_abc: &ContextKey = &vec!["a".to_string(), "b".to_string(), "c".to_string()];
         let commit1;
-        let mut storage = get_storage();
         {
+            let db = PersistentDB::open(&db_path).unwrap();
+            let mut storage = MerkleStorage::new(Arc::new(RwLock::new(db)));
 
             let key_abx: &ContextKey = &vec!["a".to_string(), "b".to_string(), "x".to_string()];
             storage.set(key_abc, &vec![2 as u8]).unwrap();
@@ -922,6 +2525,8 @@ mod tests {
             commit1 = storage.commit(0, "".to_string(), "".to_string()).unwrap();
         }
 
+        let db = PersistentDB::open(&db_path).unwrap();
+        let storage = MerkleStorage::new(Arc::new(RwLock::new(db)));
         assert_eq!(vec![2 as u8], storage.get_history(&commit1, &key_abc).unwrap());
     }
 
@@ -937,6 +2542,804 @@ mod tests {
 
         let res = storage.get(&vec!["a".to_string()]);
         assert!(if let MerkleError::ValueNotFound { .. } = res.err().unwrap() { true } else { false });
+
+        assert!(matches!(storage.get_commit(&[0; HASH_LEN]), Err(MerkleError::EntryNotFound { .. })));
+    }
+
+    #[test]
+    #[serial]
+    fn test_mem() {
+        let mut storage = get_storage();
+        let key_abc: &ContextKey = &vec!["a".to_string(), "b".to_string(), "c".to_string()];
+        let key_ab: &ContextKey = &vec!["a".to_string(), "b".to_string()];
+        let key_abcd: &ContextKey = &vec!["a".to_string(), "b".to_string(), "c".to_string(), "d".to_string()];
+
+        storage.set(key_abc, &vec![1u8]).unwrap();
+        storage.set(&vec!["d".to_string()], &vec![2u8]).unwrap();
+        assert!(storage.mem(key_abc).unwrap());
+        assert!(!storage.dir_mem(key_abc).unwrap());
+        assert!(!storage.mem(key_ab).unwrap());
+        assert!(storage.dir_mem(key_ab).unwrap());
+        assert!(!storage.mem(key_abcd).unwrap());
+        assert!(!storage.dir_mem(key_abcd).unwrap());
+        assert!(matches!(storage.mem(&vec![]), Err(MerkleError::KeyEmpty)));
+
+        let commit1 = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+        storage.delete(key_abc).unwrap();
+        assert!(!storage.mem(key_abc).unwrap());
+        assert!(!storage.dir_mem(key_ab).unwrap());
+        assert!(storage.mem_history(&commit1, key_abc).unwrap());
+        assert!(storage.dir_mem_history(&commit1, key_ab).unwrap());
+        assert!(!storage.dir_mem_history(&commit1, key_abc).unwrap());
+    }
+
+    #[test]
+    #[serial]
+    fn test_walk() {
+        let mut storage = get_storage();
+
+        storage.set(&key("a/b/c"), &vec![1u8]).unwrap();
+        storage.set(&key("a/b/d/e"), &vec![2u8]).unwrap();
+        storage.set(&key("a/x"), &vec![3u8]).unwrap();
+        storage.set(&key("b"), &vec![4u8]).unwrap();
+        let commit = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+
+        let values: Vec<_> = storage.walk(&commit, &vec![]).unwrap().map(|r| r.unwrap()).collect();
+        assert_eq!(values, vec![
+            (key("a/b/c"), Some(vec![1u8])),
+            (key("a/b/d/e"), Some(vec![2u8])),
+            (key("a/x"), Some(vec![3u8])),
+            (key("b"), Some(vec![4u8])),
+        ]);
+
+        let keys: Vec<_> = storage.walk(&commit, &key("a")).unwrap().keys_only().map(|r| r.unwrap()).collect();
+        assert_eq!(keys, vec![(key("a/b/c"), None), (key("a/b/d/e"), None), (key("a/x"), None)]);
+
+        let shallow: Vec<_> = storage.walk(&commit, &key("a")).unwrap().max_depth(2).map(|r| r.unwrap().0).collect();
+        assert_eq!(shallow, vec![key("a/b/c"), key("a/x")]);
+
+        // trees at the depth limit are not loaded, so a missing one goes unnoticed
+        let root = storage.get_tree(&storage.get_commit(&commit).unwrap().root_hash).unwrap();
+        let tree_d = storage.find_node(&root, &key("a/b/d")).unwrap().unwrap();
+        KeyValueStoreWithSchema::<MerkleStorage>::delete(&mut *storage.db.write().unwrap(), &tree_d.entry_hash).unwrap();
+        let shallow: Vec<_> = storage.walk(&commit, &key("a")).unwrap().max_depth(2).map(|r| r.unwrap().0).collect();
+        assert_eq!(shallow, vec![key("a/b/c"), key("a/x")]);
+        assert!(storage.walk(&commit, &key("a")).unwrap().any(|r| r.is_err()));
+
+        let first = storage.walk(&commit, &vec![]).unwrap().next().unwrap().unwrap();
+        assert_eq!(first.0, key("a/b/c"));
+
+        assert_eq!(storage.walk(&commit, &key("b")).unwrap().count(), 0);
+        assert_eq!(storage.walk(&commit, &key("z")).unwrap().count(), 0);
+    }
+
+    #[test]
+    #[serial]
+    fn test_delete_and_remove_recursively() {
+        let mut storage = get_storage();
+
+        storage.set(&key("a/b/c"), &vec![1u8]).unwrap();
+        storage.set(&key("a/b/d"), &vec![2u8]).unwrap();
+        storage.set(&key("x"), &vec![3u8]).unwrap();
+
+        // directories are not deleted, missing keys are ignored
+        storage.delete(&key("a/b")).unwrap();
+        storage.delete(&key("a/z")).unwrap();
+        assert!(storage.mem(&key("a/b/c")).unwrap());
+
+        storage.delete(&key("a/b/c")).unwrap();
+        assert!(!storage.mem(&key("a/b/c")).unwrap());
+        assert!(storage.mem(&key("a/b/d")).unwrap());
+
+        storage.remove_recursively(&key("a/b")).unwrap();
+        assert!(!storage.mem(&key("a/b/d")).unwrap());
+        assert!(!storage.dir_mem(&key("a")).unwrap());
+
+        // removing the last value leaves an empty root
+        storage.delete(&key("x")).unwrap();
+        let commit = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+        let empty_commit = {
+            let mut storage = get_storage();
+            storage.commit(0, "".to_string(), "".to_string()).unwrap()
+        };
+        assert_eq!(commit, empty_commit);
+    }
+
+    #[test]
+    #[serial]
+    fn test_copy_values_and_from_commit() {
+        let mut storage = get_storage();
+
+        storage.set(&key("a/b/c"), &vec![1u8]).unwrap();
+        storage.set(&key("a/d"), &vec![2u8]).unwrap();
+        storage.copy(&key("a/d"), &key("x/d")).unwrap();
+        assert_eq!(storage.get(&key("x/d")).unwrap(), vec![2u8]);
+
+        // missing source copies nothing
+        storage.copy(&key("a/z"), &key("y")).unwrap();
+        assert!(!storage.mem(&key("y")).unwrap());
+        assert!(!storage.dir_mem(&key("y")).unwrap());
+        let commit1 = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+
+        storage.remove_recursively(&key("a")).unwrap();
+        storage.commit(0, "".to_string(), "".to_string()).unwrap();
+        storage.copy_from(&commit1, &key("a/b"), &key("restored/b")).unwrap();
+        storage.copy_from(&commit1, &key("a/d"), &key("restored/d")).unwrap();
+        let commit3 = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+
+        assert_eq!(storage.get_history(&commit3, &key("restored/b/c")).unwrap(), vec![1u8]);
+        assert_eq!(storage.get_history(&commit3, &key("restored/d")).unwrap(), vec![2u8]);
+        assert!(storage.get_history(&commit3, &key("a/d")).is_err());
+    }
+
+    #[test]
+    #[serial]
+    fn test_apply_changes() {
+        let set = |k: &str, v: u8| ContextChange::Set { key: key(k), value: vec![v] };
+        let changes = vec![
+            set("a/b/c", 1),
+            set("a/b/d", 2),
+            set("a/x", 3),
+            set("e", 4),
+            ContextChange::Copy { from_key: key("a/b"), to_key: key("f/b") },
+            set("a/b/d", 5),
+            ContextChange::Delete { key: key("a/b") },
+            ContextChange::Delete { key: key("a/x") },
+            set("e/g", 6),
+            ContextChange::Delete { key: key("e") },
+            ContextChange::RemoveRecursively { key: key("a/b/c/z") },
+            ContextChange::Copy { from_key: key("a/b/d"), to_key: key("h") },
+            ContextChange::RemoveRecursively { key: key("a") },
+            set("i/j", 7),
+            ContextChange::Copy { from_key: key("i"), to_key: key("k") },
+        ];
+
+        let mut storage = get_storage();
+        storage.set(&key("z"), &vec![0u8]).unwrap();
+        for change in changes.clone() {
+            match change {
+                ContextChange::Set { key, value } => storage.set(&key, &value),
+                ContextChange::Delete { key } => storage.delete(&key),
+                ContextChange::RemoveRecursively { key } => storage.remove_recursively(&key),
+                ContextChange::Copy { from_key, to_key } => storage.copy(&from_key, &to_key),
+            }.unwrap();
+        }
+        let expected = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+
+        let mut storage = get_storage();
+        storage.set_many(&[(key("z"), vec![0u8])]).unwrap();
+        storage.apply_changes(changes).unwrap();
+        let commit = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+        assert_eq!(commit, expected);
+        assert_eq!(storage.get(&key("f/b/d")).unwrap(), vec![2u8]);
+        assert_eq!(storage.get(&key("h")).unwrap(), vec![5u8]);
+        assert_eq!(storage.get(&key("k/j")).unwrap(), vec![7u8]);
+        assert!(storage.dir_mem(&key("e")).unwrap());
+        assert!(!storage.dir_mem(&key("a")).unwrap());
+
+        // removing everything leaves an empty root
+        storage.apply_changes(vec![
+            ContextChange::RemoveRecursively { key: key("e") },
+            ContextChange::RemoveRecursively { key: key("f") },
+            ContextChange::RemoveRecursively { key: key("h") },
+            ContextChange::RemoveRecursively { key: key("i") },
+            ContextChange::RemoveRecursively { key: key("k") },
+            ContextChange::Delete { key: key("z") },
+        ]).unwrap();
+        let commit = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+        assert!(storage.walk(&commit, &vec![]).unwrap().next().is_none());
+    }
+
+    #[test]
+    #[serial]
+    fn test_deferred_hashing() {
+        let run = |storage: &mut MerkleStorage| -> EntryHash {
+            for i in 0..10u8 {
+                storage.set(&key(&format!("a/b/{}", i)), &vec![i]).unwrap();
+                storage.set(&key("a/c"), &vec![i]).unwrap();
+            }
+            storage.copy(&key("a/b"), &key("d")).unwrap();
+            storage.delete(&key("a/b/0")).unwrap();
+            assert_eq!(storage.get(&key("d/0")).unwrap(), vec![0u8]);
+            storage.remove_recursively(&key("a/b")).unwrap();
+            storage.set(&key("e"), &vec![1u8]).unwrap();
+            storage.commit(0, "".to_string(), "".to_string()).unwrap()
+        };
+
+        let mut eager = get_storage();
+        let expected = run(&mut eager);
+
+        let mut deferred = get_storage().with_deferred_hashing(true);
+        deferred.set(&key("x"), &vec![1u8]).unwrap();
+        deferred.delete(&key("x")).unwrap();
+        deferred.set(&key("a/b/z"), &vec![1u8]).unwrap();
+        deferred.set(&key("y/z"), &vec![2u8]).unwrap();
+        deferred.delete(&key("y/z")).unwrap();
+        let staged_before = deferred.staged.len();
+        // nothing is hashed until the staged root hash is needed, only the genesis root and blobs are staged
+        assert!(!deferred.working_trees.is_empty());
+        assert_eq!(staged_before, 3);
+
+        // reads are served from modified trees, trees left empty are absent
+        assert_eq!(deferred.get(&key("a/b/z")).unwrap(), vec![1u8]);
+        assert!(matches!(deferred.get(&key("a/b")), Err(MerkleError::ValueIsNotABlob { .. })));
+        assert!(matches!(deferred.get(&key("x")), Err(MerkleError::ValueNotFound { .. })));
+        assert!(matches!(deferred.get(&key("a/b/z/w")), Err(MerkleError::ValueNotFound { .. })));
+        assert!(deferred.mem(&key("a/b/z")).unwrap());
+        assert!(!deferred.mem(&key("a/b")).unwrap());
+        assert!(deferred.dir_mem(&key("a/b")).unwrap());
+        assert!(!deferred.dir_mem(&key("y")).unwrap());
+        assert_eq!(deferred.get_by_prefix(&key("a")).unwrap(), Some(vec![(key("a/b/z"), vec![1u8])]));
+        assert_eq!(deferred.get_by_prefix(&vec![]).unwrap(), Some(vec![(key("a/b/z"), vec![1u8])]));
+        assert_eq!(deferred.get_by_prefix(&key("y")).unwrap(), None);
+        assert!(!deferred.working_trees.is_empty());
+        assert_eq!(deferred.staged.len(), staged_before);
+
+        let root_hash = deferred.get_staged_root_hash().unwrap();
+        assert_eq!(deferred.staged.len(), staged_before + 3);
+        assert_eq!(root_hash, deferred.hash_tree(deferred.current_stage_tree.as_ref().unwrap()));
+
+        deferred.remove_recursively(&key("a")).unwrap();
+        assert_eq!(run(&mut deferred), expected);
+        assert_eq!(deferred.get(&key("d/9")).unwrap(), vec![9u8]);
+
+        // grafting from a commit only modifies trees in memory as well
+        deferred.copy_from(&expected, &key("d"), &key("f/d")).unwrap();
+        assert!(!deferred.working_trees.is_empty());
+        assert_eq!(deferred.get(&key("f/d/9")).unwrap(), vec![9u8]);
+        eager.copy_from(&expected, &key("d"), &key("f/d")).unwrap();
+        assert_eq!(deferred.get_staged_root_hash().unwrap(), eager.get_staged_root_hash().unwrap());
+
+        for storage in &mut [eager, deferred] {
+            assert!(matches!(storage.set(&vec![], &vec![1u8]), Err(MerkleError::KeyEmpty)));
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn test_prune_staged() {
+
+        let mut storage = get_storage();
+        for i in 0..20u8 {
+            storage.set(&key("a/b/c"), &vec![i]).unwrap();
+        }
+        storage.set(&key("a/d"), &vec![0u8]).unwrap();
+        let bytes_before = storage.map_stats.staged_area_bytes;
+        // root, a, a/b and blobs of c and d are reachable
+        assert!(storage.prune_staged() > 0);
+        assert_eq!(storage.staged.len(), 5);
+        assert_eq!(storage.map_stats.staged_area_elems, 5);
+        assert!(storage.map_stats.staged_area_bytes < bytes_before);
+        assert_eq!(storage.map_stats.staged_prunes, 1);
+        assert_eq!(storage.get(&key("a/b/c")).unwrap(), vec![19u8]);
+        let commit = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+        assert_eq!(storage.get_history(&commit, &key("a/d")).unwrap(), vec![0u8]);
+
+        // automatic pruning keeps the staging area small, also with deferred hashing
+        for deferred_hashing in &[false, true] {
+            let mut storage = get_storage()
+                .with_deferred_hashing(*deferred_hashing)
+                .with_staged_prune_trigger(StagedPruneTrigger::Elems(10));
+            for i in 0..100u8 {
+                storage.set(&key(&format!("a/{}", i % 3)), &vec![i]).unwrap();
+                assert!(storage.staged.len() <= 11);
+            }
+            assert!(storage.map_stats.staged_prunes > 0);
+            let commit = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+            assert_eq!(storage.get_history(&commit, &key("a/0")).unwrap(), vec![99u8]);
+            assert_eq!(storage.get_history(&commit, &key("a/2")).unwrap(), vec![98u8]);
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn test_entry_cache() {
+        let mut storage = get_storage().with_entry_cache(100, 1_000_000);
+        storage.set(&key("data/a"), &vec![1u8]).unwrap();
+        storage.set(&key("data/b"), &vec![2u8]).unwrap();
+        let commit1 = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+        storage.set(&key("data/a"), &vec![3u8]).unwrap();
+        let commit2 = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+
+        assert_eq!(storage.get_history(&commit1, &key("data/a")).unwrap(), vec![1u8]);
+        let before = storage.get_merkle_stats().unwrap().cache_stats;
+        assert_eq!(storage.get_history(&commit1, &key("data/b")).unwrap(), vec![2u8]);
+        let after = storage.get_merkle_stats().unwrap().cache_stats;
+        // commit, root and data trees are cached, blob of b is not
+        assert_eq!(after.hits - before.hits, 3);
+        assert_eq!(after.misses - before.misses, 1);
+        assert!(after.entries > 0 && after.bytes > 0);
+
+        // entries removed by gc are not served from cache
+        storage.gc(&[commit2]).unwrap();
+        assert!(storage.get_history(&commit1, &key("data/a")).is_err());
+        assert_eq!(storage.get_history(&commit2, &key("data/a")).unwrap(), vec![3u8]);
+    }
+
+    #[test]
+    #[serial]
+    fn test_gc() {
+        let mut storage = get_storage();
+        let key_abc: &ContextKey = &vec!["a".to_string(), "b".to_string(), "c".to_string()];
+        let key_abx: &ContextKey = &vec!["a".to_string(), "b".to_string(), "x".to_string()];
+        let key_d: &ContextKey = &vec!["d".to_string()];
+
+        storage.set(key_abc, &vec![1u8]).unwrap();
+        storage.set(key_d, &vec![9u8]).unwrap();
+        let commit1 = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+        storage.set(key_abc, &vec![2u8]).unwrap();
+        let commit2 = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+        storage.set(key_abx, &vec![3u8]).unwrap();
+        let commit3 = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+        let keys_before = storage.get_merkle_stats().unwrap().db_stats.keys;
+
+        let stats = storage.gc_keep_last(2).unwrap();
+        // commit1, its root, trees a and a/b and blob 1
+        assert_eq!(stats.entries_removed, 5);
+        assert!(stats.bytes_removed > 0);
+        assert_eq!(storage.get_merkle_stats().unwrap().db_stats.keys, keys_before - 5);
+
+        assert!(storage.get_history(&commit1, key_abc).is_err());
+        assert_eq!(storage.get_history(&commit2, key_abc).unwrap(), vec![2u8]);
+        assert_eq!(storage.get_history(&commit2, key_d).unwrap(), vec![9u8]);
+        assert_eq!(storage.get_history(&commit3, key_abx).unwrap(), vec![3u8]);
+
+        // collecting again finds nothing, history ends at the oldest retained commit
+        assert_eq!(storage.gc(&[commit2]).unwrap().entries_removed, 0);
+
+        // the staging area is retained as well
+        storage.set(key_abc, &vec![4u8]).unwrap();
+        // commit2, its root, trees a and a/b; blob 2 is still referenced by commit3
+        let stats = storage.gc(&[]).unwrap();
+        assert_eq!(stats.entries_removed, 4);
+        let commit4 = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+        assert_eq!(storage.get_history(&commit4, key_abx).unwrap(), vec![3u8]);
+        assert_eq!(storage.get_history(&commit4, key_d).unwrap(), vec![9u8]);
+    }
+
+    #[test]
+    #[serial]
+    fn test_gc_abandoned_branch() {
+        let mut storage = get_storage();
+        let key_a: &ContextKey = &vec!["a".to_string()];
+
+        storage.set(key_a, &vec![1u8]).unwrap();
+        let commit1 = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+        storage.set(key_a, &vec![2u8]).unwrap();
+        let commit2 = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+        storage.checkout(commit1).unwrap();
+        storage.set(key_a, &vec![3u8]).unwrap();
+        let commit3 = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+
+        // commit1 and commit2 with their roots and blobs, commit2 is not in the history of commit3
+        let stats = storage.gc(&[commit3]).unwrap();
+        assert_eq!(stats.entries_removed, 6);
+        assert!(matches!(storage.get_commit(&commit2), Err(MerkleError::EntryNotFound { .. })));
+        assert!(matches!(storage.get_commit(&commit1), Err(MerkleError::EntryNotFound { .. })));
+        assert_eq!(storage.get_history(&commit3, key_a).unwrap(), vec![3u8]);
+    }
+
+    #[test]
+    #[serial]
+    fn test_gc_keeps_refs() {
+        let mut storage = get_storage();
+        let key_a: &ContextKey = &vec!["a".to_string()];
+
+        storage.set(key_a, &vec![1u8]).unwrap();
+        let commit1 = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+        storage.create_ref("block/1", &commit1).unwrap();
+        storage.set(key_a, &vec![2u8]).unwrap();
+        storage.commit(0, "".to_string(), "".to_string()).unwrap();
+
+        storage.gc_keep_last(1).unwrap();
+        assert_eq!(storage.get_ref("block/1").unwrap(), Some(commit1));
+        storage.checkout("block/1").unwrap();
+        assert_eq!(storage.get(key_a).unwrap(), vec![1u8]);
+    }
+
+    #[test]
+    #[serial]
+    fn test_gc_fails_on_missing_tree() {
+        let mut storage = get_storage();
+        storage.set(&key("a/b"), &vec![1u8]).unwrap();
+        storage.set(&key("c"), &vec![2u8]).unwrap();
+        let commit = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+
+        let root = storage.get_tree(&storage.get_commit(&commit).unwrap().root_hash).unwrap();
+        let tree_a = storage.find_node(&root, &key("a")).unwrap().unwrap();
+        let count = |storage: &MerkleStorage| {
+            let db = storage.db.read().unwrap();
+            KeyValueStoreWithSchema::<MerkleStorage>::iterator(&*db, IteratorMode::Start).unwrap().count()
+        };
+        KeyValueStoreWithSchema::<MerkleStorage>::delete(&mut *storage.db.write().unwrap(), &tree_a.entry_hash).unwrap();
+        let entries_before = count(&storage);
+
+        // what is reachable from the missing tree is unknown, so nothing is swept
+        assert!(matches!(storage.gc(&[]), Err(MerkleError::EntryNotFound { .. })));
+        assert_eq!(count(&storage), entries_before);
+    }
+
+    #[test]
+    #[serial]
+    fn test_gc_keeps_merge_parents() {
+        let mut storage = get_storage();
+        storage.set(&key("a"), &vec![1u8]).unwrap();
+        let base = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+        storage.set(&key("b"), &vec![2u8]).unwrap();
+        let theirs = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+        storage.checkout(base).unwrap();
+        storage.set(&key("c"), &vec![3u8]).unwrap();
+        let ours = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+
+        // the pending merge parent survives, although no retained commit leads to it yet
+        storage.merge(&theirs, |_| Ok(MergeResolution::Ours)).unwrap();
+        storage.gc_keep_last(1).unwrap();
+        assert_eq!(storage.get_history(&theirs, &key("b")).unwrap(), vec![2u8]);
+        let merge = storage.commit(0, "".to_string(), "merge".to_string()).unwrap();
+        assert_eq!(storage.log(&merge).next().unwrap().unwrap().parent_hashes, vec![ours, theirs]);
+    }
+
+    #[test]
+    #[serial]
+    fn test_proofs() {
+        let mut storage = get_storage();
+        let key_abc: &ContextKey = &vec!["a".to_string(), "b".to_string(), "c".to_string()];
+        let key_abx: &ContextKey = &vec!["a".to_string(), "b".to_string(), "x".to_string()];
+        let key_ab: &ContextKey = &vec!["a".to_string(), "b".to_string()];
+        let key_zy: &ContextKey = &vec!["z".to_string(), "y".to_string()];
+        let key_abcd: &ContextKey = &vec!["a".to_string(), "b".to_string(), "c".to_string(), "d".to_string()];
+        storage.set(key_abc, &vec![1u8, 2u8]).unwrap();
+        storage.set(&vec!["d".to_string()], &vec![3u8]).unwrap();
+        let commit = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+
+        // inclusion
+        let proof = storage.get_proof(&commit, key_abc).unwrap();
+        assert_eq!(proof.verify(&commit, key_abc).unwrap(), Some(vec![1u8, 2u8]));
+        let proof: MerkleProof = bincode::deserialize(&bincode::serialize(&proof).unwrap()).unwrap();
+        assert_eq!(proof.verify(&commit, key_abc).unwrap(), Some(vec![1u8, 2u8]));
+
+        // non-inclusion: missing key, missing directory, directory under key, path through a value
+        for key in &[key_abx, key_zy, key_ab, key_abcd] {
+            let proof = storage.get_proof(&commit, key).unwrap();
+            assert_eq!(proof.verify(&commit, key).unwrap(), None);
+        }
+
+        // proof for a different key or commit is rejected
+        let proof = storage.get_proof(&commit, key_abc).unwrap();
+        assert!(proof.verify(&commit, key_abx).is_err());
+        assert!(proof.verify(&[0; HASH_LEN], key_abc).is_err());
+
+        // tampered value or truncated path is rejected
+        let mut tampered = proof.clone();
+        tampered.value = Some(vec![9u8]);
+        assert!(tampered.verify(&commit, key_abc).is_err());
+        let mut truncated = proof.clone();
+        truncated.trees.pop();
+        truncated.value = None;
+        assert!(truncated.verify(&commit, key_abc).is_err());
+    }
+
+    #[test]
+    #[serial]
+    fn test_diff() {
+        let mut storage = get_storage();
+
+        storage.set(&key("data/a/x"), &vec![1u8]).unwrap();
+        storage.set(&key("data/a/y"), &vec![2u8]).unwrap();
+        storage.set(&key("data/b"), &vec![3u8]).unwrap();
+        storage.set(&key("data/c/z"), &vec![4u8]).unwrap();
+        storage.set(&key("other/o"), &vec![5u8]).unwrap();
+        let commit1 = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+
+        storage.set(&key("data/a/x"), &vec![10u8]).unwrap();
+        storage.delete(&key("data/a/y")).unwrap();
+        storage.set(&key("data/b/w"), &vec![6u8]).unwrap();
+        storage.set(&key("data/d"), &vec![7u8]).unwrap();
+        storage.set(&key("other/o"), &vec![8u8]).unwrap();
+        let commit2 = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+
+        assert_eq!(storage.diff(&commit1, &commit2, &key("data")).unwrap(), vec![
+            DiffEntry::Modified { key: key("data/a/x"), old_value: vec![1u8], new_value: vec![10u8] },
+            DiffEntry::Removed { key: key("data/a/y"), value: vec![2u8] },
+            DiffEntry::Removed { key: key("data/b"), value: vec![3u8] },
+            DiffEntry::Added { key: key("data/b/w"), value: vec![6u8] },
+            DiffEntry::Added { key: key("data/d"), value: vec![7u8] },
+        ]);
+        assert_eq!(storage.diff(&commit1, &commit2, &vec![]).unwrap().len(), 6);
+        assert!(storage.diff(&commit2, &commit2, &vec![]).unwrap().is_empty());
+        assert_eq!(storage.diff(&commit2, &commit1, &key("other")).unwrap(), vec![
+            DiffEntry::Modified { key: key("other/o"), old_value: vec![8u8], new_value: vec![5u8] },
+        ]);
+        // value at the prefix itself turned into a tree
+        assert_eq!(storage.diff(&commit1, &commit2, &key("data/b")).unwrap(), vec![
+            DiffEntry::Removed { key: key("data/b"), value: vec![3u8] },
+            DiffEntry::Added { key: key("data/b/w"), value: vec![6u8] },
+        ]);
+
+        // entries missing in the database fail the diff
+        let root2 = storage.get_tree(&storage.get_commit(&commit2).unwrap().root_hash).unwrap();
+        let blob = storage.find_node(&root2, &key("data/b/w")).unwrap().unwrap();
+        KeyValueStoreWithSchema::<MerkleStorage>::delete(&mut *storage.db.write().unwrap(), &blob.entry_hash).unwrap();
+        assert!(matches!(storage.diff(&commit1, &commit2, &key("data")), Err(MerkleError::EntryNotFound { .. })));
+    }
+
+    #[test]
+    #[serial]
+    fn test_log() {
+        let mut storage = get_storage();
+        let key_a: &ContextKey = &vec!["a".to_string()];
+        let mut commits = Vec::new();
+        for (time, author) in [(10, "alice"), (20, "bob"), (30, "alice"), (40, "bob")].iter() {
+            storage.set(key_a, &vec![*time as u8]).unwrap();
+            commits.push(storage.commit(*time, author.to_string(), format!("at {}", time)).unwrap());
+        }
+
+        let log: Vec<CommitInfo> = storage.log(&commits[3]).map(Result::unwrap).collect();
+        assert_eq!(log.iter().map(|c| c.hash).collect::<Vec<_>>(), commits.iter().rev().cloned().collect::<Vec<_>>());
+        assert_eq!(log[0].parent_hashes, vec![commits[2]]);
+        assert_eq!(log[0].author, "bob");
+        assert_eq!(log[0].message, "at 40");
+        assert!(log[3].parent_hashes.is_empty());
+
+        let by_alice: Vec<u64> = storage.log(&commits[3]).author("alice").map(|c| c.unwrap().time).collect();
+        assert_eq!(by_alice, vec![30, 10]);
+        let in_range: Vec<u64> = storage.log(&commits[3]).time_range(15, 30).map(|c| c.unwrap().time).collect();
+        assert_eq!(in_range, vec![30, 20]);
+        let filtered: Vec<u64> = storage.log(&commits[2]).time_range(15, 40).author("bob").map(|c| c.unwrap().time).collect();
+        assert_eq!(filtered, vec![20]);
+
+        assert!(storage.log(&[0; HASH_LEN]).next().unwrap().is_err());
+
+        // history ends at the oldest commit retained by gc
+        storage.gc_keep_last(2).unwrap();
+        assert_eq!(storage.log(&commits[3]).count(), 2);
+    }
+
+    #[test]
+    #[serial]
+    fn test_key_history() {
+        let mut storage = get_storage();
+        let key_abc: &ContextKey = &vec!["a".to_string(), "b".to_string(), "c".to_string()];
+        let key_abx: &ContextKey = &vec!["a".to_string(), "b".to_string(), "x".to_string()];
+        let key_d: &ContextKey = &vec!["d".to_string()];
+
+        storage.set(key_abc, &vec![1u8]).unwrap();
+        let commit1 = storage.commit(1, "".to_string(), "".to_string()).unwrap();
+        storage.set(key_d, &vec![2u8]).unwrap();
+        let _commit2 = storage.commit(2, "".to_string(), "".to_string()).unwrap();
+        storage.set(key_abx, &vec![3u8]).unwrap();
+        let _commit3 = storage.commit(3, "".to_string(), "".to_string()).unwrap();
+        storage.set(key_abc, &vec![4u8]).unwrap();
+        let commit4 = storage.commit(4, "".to_string(), "".to_string()).unwrap();
+        storage.delete(key_abc).unwrap();
+        let commit5 = storage.commit(5, "".to_string(), "".to_string()).unwrap();
+        storage.set(key_abc, &vec![4u8]).unwrap();
+        let commit6 = storage.commit(6, "".to_string(), "".to_string()).unwrap();
+
+        let history = storage.get_key_history(&commit6, key_abc).unwrap();
+        let changes: Vec<(EntryHash, Option<ContextValue>)> = history.into_iter()
+            .map(|entry| (entry.commit.hash, entry.value))
+            .collect();
+        assert_eq!(changes, vec![
+            (commit6, Some(vec![4u8])),
+            (commit5, None),
+            (commit4, Some(vec![4u8])),
+            (commit1, Some(vec![1u8])),
+        ]);
+
+        assert_eq!(storage.get_key_history(&commit6, key_d).unwrap().len(), 1);
+        assert!(storage.get_key_history(&commit6, &vec!["nope".to_string()]).unwrap().is_empty());
+        assert!(storage.get_key_history(&commit6, &vec![]).is_err());
+
+        // change in the oldest retained commit cannot be determined after gc
+        storage.gc_keep_last(3).unwrap();
+        assert_eq!(storage.get_key_history(&commit6, key_abc).unwrap().len(), 2);
+    }
+
+    #[test]
+    #[serial]
+    fn test_refs() {
+        let mut storage = get_storage();
+        let key_a: &ContextKey = &vec!["a".to_string()];
+        storage.set(key_a, &vec![1u8]).unwrap();
+        let commit1 = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+        storage.set(key_a, &vec![2u8]).unwrap();
+        let commit2 = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+
+        storage.create_ref("main", &commit1).unwrap();
+        storage.create_ref("block/abc", &commit2).unwrap();
+        assert!(storage.create_ref("main", &commit2).is_err());
+        assert!(storage.create_ref("missing", &[0; HASH_LEN]).is_err());
+        assert_eq!(storage.get_ref("main").unwrap(), Some(commit1));
+        assert_eq!(storage.get_ref("nope").unwrap(), None);
+        assert_eq!(storage.list_refs().unwrap(), vec![
+            ("block/abc".to_string(), commit2),
+            ("main".to_string(), commit1),
+        ]);
+        // refs do not show up as merkle entries
+        assert_eq!(storage.diff(&commit1, &commit2, &vec![]).unwrap().len(), 1);
+
+        // compare and swap
+        assert!(storage.update_ref("main", Some(&commit2), &commit2).is_err());
+        assert!(storage.update_ref("main", None, &commit2).is_err());
+        storage.update_ref("main", Some(&commit1), &commit2).unwrap();
+        assert_eq!(storage.get_ref("main").unwrap(), Some(commit2));
+
+        storage.delete_ref("block/abc").unwrap();
+        assert!(storage.delete_ref("block/abc").is_err());
+        assert_eq!(storage.list_refs().unwrap().len(), 1);
+
+        // checkout by name moves the reference with new commits, checkout by hash does not
+        storage.update_ref("main", Some(&commit2), &commit1).unwrap();
+        storage.checkout("main").unwrap();
+        assert_eq!(storage.get(key_a).unwrap(), vec![1u8]);
+        storage.set(key_a, &vec![3u8]).unwrap();
+        let commit3 = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+        assert_eq!(storage.get_ref("main").unwrap(), Some(commit3));
+
+        storage.checkout(commit2).unwrap();
+        assert_eq!(storage.get_current_ref(), None);
+        storage.set(key_a, &vec![4u8]).unwrap();
+        storage.commit(0, "".to_string(), "".to_string()).unwrap();
+        assert_eq!(storage.get_ref("main").unwrap(), Some(commit3));
+        assert!(storage.checkout("nope").is_err());
+    }
+
+    #[test]
+    #[serial]
+    fn test_commit_ref_conflict() {
+        let mut storage = get_storage();
+        let key_a: &ContextKey = &vec!["a".to_string()];
+        storage.set(key_a, &vec![1u8]).unwrap();
+        let commit1 = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+        storage.set(key_a, &vec![2u8]).unwrap();
+        let commit2 = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+
+        storage.create_ref("main", &commit1).unwrap();
+        storage.checkout("main").unwrap();
+        storage.set(key_a, &vec![3u8]).unwrap();
+        // moved by someone else in the meantime
+        storage.update_ref("main", Some(&commit1), &commit2).unwrap();
+
+        let result = storage.commit(0, "".to_string(), "".to_string());
+        assert!(matches!(result, Err(MerkleError::RefConflict { .. })));
+        assert_eq!(storage.get_last_commit_hash(), Some(commit1));
+        assert_eq!(storage.get_ref("main").unwrap(), Some(commit2));
+        assert_eq!(storage.get(key_a).unwrap(), vec![3u8]);
+    }
+
+    #[test]
+    #[serial]
+    fn test_commit_hash_multiple_parents() {
+        // commits of `test_commit_hash`, with known irmin hashes
+        let mut storage = get_storage();
+        storage.set(&vec!["a".to_string()], &vec![97, 98, 99]).unwrap();
+        let parent1 = storage.commit(0, "Tezos".to_string(), "Genesis".to_string()).unwrap();
+        storage.set(&vec!["data".to_string(), "x".to_string()], &vec![97]).unwrap();
+        let parent2 = storage.commit(0, "Tezos".to_string(), "".to_string()).unwrap();
+        assert_eq!(hex::encode(parent2), "ca7bc7022ffbd35acc97f7defb00c486bb7f4d19a2d62790d5949775eb74f3c8");
+
+        let root_hash = storage.get_staged_root_hash().unwrap();
+        let commit = |parents: Vec<EntryHash>| Commit {
+            parent_commit_hashes: parents,
+            root_hash,
+            time: 0,
+            author: "Tezos".to_string(),
+            message: "merge".to_string(),
+        };
+        // irmin encodes the list of parents like any other list, sorted: [len: u64]([len: u64][hash])*
+        assert_eq!(hex::encode(hash_commit(&commit(vec![parent2, parent1]))),
+                   "799d9b1cb5e08ada362225c2133e31b9961da9febb0132e0884dbcbc70930b16");
+        assert_eq!(hash_commit(&commit(vec![parent1, parent2])), hash_commit(&commit(vec![parent2, parent1])));
+    }
+
+    #[test]
+    #[serial]
+    fn test_merge() {
+        let mut storage = get_storage();
+
+        storage.set(&key("data/x"), &vec![1u8]).unwrap();
+        storage.set(&key("data/y"), &vec![2u8]).unwrap();
+        storage.set(&key("data/z"), &vec![3u8]).unwrap();
+        let base = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+
+        storage.set(&key("data/x"), &vec![10u8]).unwrap();
+        storage.set(&key("data/z"), &vec![30u8]).unwrap();
+        storage.set(&key("theirs"), &vec![4u8]).unwrap();
+        let theirs = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+
+        storage.checkout(base).unwrap();
+        storage.delete(&key("data/y")).unwrap();
+        storage.set(&key("data/z"), &vec![31u8]).unwrap();
+        storage.set(&key("ours"), &vec![5u8]).unwrap();
+        let ours = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+
+        assert_eq!(storage.find_merge_base(&ours, &theirs).unwrap(), Some(base));
+        assert_eq!(storage.find_merge_base(&base, &theirs).unwrap(), Some(base));
+        assert_eq!(storage.find_merge_base(&theirs, &theirs).unwrap(), Some(theirs));
+
+        // unresolved conflict fails the merge
+        assert!(storage.merge(&theirs, |c| Err(MerkleError::MergeConflict { key: c.key.join("/") })).is_err());
+
+        let mut conflicts = Vec::new();
+        storage.checkout(ours).unwrap();
+        storage.merge(&theirs, |conflict| {
+            conflicts.push(conflict.clone());
+            Ok(MergeResolution::Value(Some(vec![99u8])))
+        }).unwrap();
+        assert_eq!(conflicts, vec![MergeConflict {
+            key: key("data/z"),
+            base: Some(vec![3u8]),
+            ours: Some(vec![31u8]),
+            theirs: Some(vec![30u8]),
+        }]);
+        assert_eq!(storage.get(&key("data/x")).unwrap(), vec![10u8]);
+        assert!(storage.get(&key("data/y")).is_err());
+        assert_eq!(storage.get(&key("data/z")).unwrap(), vec![99u8]);
+        assert_eq!(storage.get(&key("ours")).unwrap(), vec![5u8]);
+        assert_eq!(storage.get(&key("theirs")).unwrap(), vec![4u8]);
+
+        // merging a pending merge parent again does not add a parent
+        storage.merge(&theirs, |_| Ok(MergeResolution::Ours)).unwrap();
+        assert_eq!(storage.get(&key("data/z")).unwrap(), vec![99u8]);
+
+        let merge = storage.commit(0, "".to_string(), "merge".to_string()).unwrap();
+        let info = storage.log(&merge).next().unwrap().unwrap();
+        assert_eq!(info.parent_hashes, vec![ours, theirs]);
+        assert_eq!(storage.get_history(&merge, &key("data/x")).unwrap(), vec![10u8]);
+
+        // merging again is a no-op, the next commit has a single parent
+        storage.merge(&theirs, |_| Ok(MergeResolution::Ours)).unwrap();
+        storage.set(&key("data/x"), &vec![11u8]).unwrap();
+        let next = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+        assert_eq!(storage.log(&next).next().unwrap().unwrap().parent_hashes, vec![merge]);
+
+        // with deferred hashing, the merged root is only hashed by the commit
+        let mut deferred = MerkleStorage::new(storage.db.clone()).with_deferred_hashing(true);
+        deferred.checkout(ours).unwrap();
+        deferred.merge(&theirs, |_| Ok(MergeResolution::Value(Some(vec![99u8])))).unwrap();
+        assert!(!deferred.working_trees.is_empty());
+        assert_eq!(deferred.get(&key("data/z")).unwrap(), vec![99u8]);
+        assert_eq!(deferred.commit(0, "".to_string(), "merge".to_string()).unwrap(), merge);
+    }
+
+    #[test]
+    #[serial]
+    fn test_snapshot_export_import() {
+        let mut storage = get_storage();
+        storage.set(&key("data/a/x"), &vec![1u8]).unwrap();
+        storage.commit(0, "".to_string(), "".to_string()).unwrap();
+        storage.set(&key("data/a/y"), &vec![2u8]).unwrap();
+        storage.set(&key("data/b"), &vec![1u8]).unwrap();
+        storage.copy(&key("data/a"), &key("copy")).unwrap();
+        let commit = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+
+        let mut snapshot = Vec::new();
+        // commit, 3 trees (root, data and data/a shared with copy) and 2 blobs, each exported once
+        let entries = storage.export_snapshot(&commit, &mut snapshot).unwrap();
+        assert_eq!(entries, 6);
+
+        let mut imported = get_storage();
+        assert_eq!(imported.import_snapshot(snapshot.as_slice()).unwrap(), commit);
+        assert_eq!(imported.get_merkle_stats().unwrap().db_stats.keys, 6);
+        assert_eq!(imported.get_history(&commit, &key("copy/y")).unwrap(), vec![2u8]);
+        assert_eq!(
+            imported.get_key_values_by_prefix(&commit, &vec![]).unwrap(),
+            storage.get_key_values_by_prefix(&commit, &vec![]).unwrap());
+
+        // corrupted or truncated snapshots are rejected without touching the DB
+        for corrupt in &[
+            { let mut s = snapshot.clone(); let len = s.len(); s[len - 40] ^= 1; s },
+            { let mut s = snapshot.clone(); let len = s.len(); s[len - 1] ^= 1; s },
+            snapshot[..snapshot.len() - 10].to_vec(),
+            snapshot[1..].to_vec(),
+            // bogus entry length is not allocated up front
+            { let mut s = snapshot[..SNAPSHOT_MAGIC.len() + 4 + HASH_LEN].to_vec(); s.extend_from_slice(&u32::MAX.to_be_bytes()); s },
+        ] {
+            let mut imported = get_storage();
+            assert!(imported.import_snapshot(corrupt.as_slice()).is_err());
+            assert_eq!(imported.get_merkle_stats().unwrap().db_stats.keys, 0);
+        }
     }
 
     // Test getting entire tree in string format for JSON RPC
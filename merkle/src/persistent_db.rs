@@ -0,0 +1,517 @@
+//! # PersistentDB
+//!
+//! File backed implementation of `KeyValueStoreWithSchema`.
+//!
+//! Every write (a single `put`/`delete`/`merge` or a whole `Batch`) is appended to the active
+//! segment file in the database directory as one frame, whose payload is the bincode of the
+//! `Vec<WriteOp>` it consists of, see `segment`.
+//!
+//! Each op names the column family it applies to, see `DB`.
+//!
+//! Once the active segment grows over `max_segment_size` a new one is started. Segments are
+//! named by their sequence number (`00000000000000000001.log`, ...) and replayed in that order on
+//! `open` to rebuild the in-memory index, which is a regular `DB`. A frame torn by a crash at
+//! the tail of the last segment is discarded, so a batch is either fully applied or not at all.
+//!
+//! `compact` rewrites all live key/values into fresh segments, in frames of bounded size, and
+//! removes the old ones once the new ones are synced.
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::codec::Encoder;
+use crate::database::{Batch, BatchOp, DBError, DBStats, IteratorMode, IteratorWithSchema, KeyValueStoreWithSchema, DB};
+use crate::ivec::IVec;
+use crate::schema::KeyValueSchema;
+use crate::segment::{list_segments, replay_segment, segment_path, write_frame, DEFAULT_MAX_SEGMENT_SIZE};
+
+/// Size of key/values after which `compact` starts a new frame
+const MAX_COMPACTION_FRAME_SIZE: u64 = 4 * 1024 * 1024;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum WriteOp {
+    Put(String, Vec<u8>, Vec<u8>),
+    Delete(String, Vec<u8>),
+    DeleteRange(String, Vec<u8>, Vec<u8>),
+    DropColumnFamily(String),
+}
+
+pub struct PersistentDB {
+    index: DB,
+    dir: PathBuf,
+    segments: Vec<u64>,
+    active: File,
+    active_size: u64,
+    max_segment_size: u64,
+}
+
+impl PersistentDB {
+    /// Open database stored in `path`, creating the directory if it does not exist.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, DBError> {
+        Self::open_with_segment_size(path, DEFAULT_MAX_SEGMENT_SIZE)
+    }
+
+    /// Open database stored in `path`, starting a new segment whenever the active one grows
+    /// over `max_segment_size` bytes.
+    pub fn open_with_segment_size<P: AsRef<Path>>(path: P, max_segment_size: u64) -> Result<Self, DBError> {
+        let dir = path.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let mut segments = list_segments(&dir)?;
+        let mut index = DB::new();
+        let last = segments.len().saturating_sub(1);
+        for (pos, id) in segments.iter().enumerate() {
+            replay_segment(&segment_path(&dir, *id), pos == last, |payload| {
+                let ops = bincode::deserialize(&payload).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                apply_ops(&mut index, ops);
+                Ok(())
+            })?;
+        }
+
+        if segments.is_empty() {
+            segments.push(1);
+        }
+        let active_id = *segments.last().unwrap();
+        let active = OpenOptions::new().create(true).append(true).open(segment_path(&dir, active_id))?;
+        let active_size = active.metadata()?.len();
+
+        Ok(PersistentDB {
+            index,
+            dir,
+            segments,
+            active,
+            active_size,
+            max_segment_size,
+        })
+    }
+
+    /// Directory holding the segment files
+    pub fn path(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Total size of all segment files in bytes, including overwritten and deleted values
+    pub fn disk_size(&self) -> Result<u64, DBError> {
+        let mut size = 0;
+        for id in &self.segments {
+            size += fs::metadata(segment_path(&self.dir, *id))?.len();
+        }
+        Ok(size)
+    }
+
+    /// Names of all existing column families, in order
+    pub fn column_family_names(&self) -> Vec<&str> {
+        self.index.column_family_names()
+    }
+
+    /// Remove column family `name` with all its keys. Returns false if it did not exist.
+    pub fn drop_column_family(&mut self, name: &str) -> Result<bool, DBError> {
+        if self.index.column_family(name).is_none() {
+            return Ok(false);
+        }
+        self.write_ops(vec![WriteOp::DropColumnFamily(name.to_string())])?;
+        Ok(true)
+    }
+
+    /// Rewrite all live key/values into new segments and remove the old segment files.
+    ///
+    /// Old segments are only removed once the new ones are synced. If compaction fails or is
+    /// interrupted, replaying both old and new segments still yields the same state.
+    pub fn compact(&mut self) -> Result<(), DBError> {
+        let max_frame_size = self.max_segment_size.min(MAX_COMPACTION_FRAME_SIZE);
+        let mut frames = Vec::new();
+        let mut ops = Vec::new();
+        let mut ops_size = 0;
+        for (name, family) in &self.index.families {
+            for (key, value) in family.inner.iter() {
+                ops_size += (key.len() + value.len()) as u64;
+                ops.push(WriteOp::Put(name.clone(), key.to_vec(), value.to_vec()));
+                if ops_size >= max_frame_size {
+                    frames.push(std::mem::take(&mut ops));
+                    ops_size = 0;
+                }
+            }
+        }
+        if !ops.is_empty() {
+            frames.push(ops);
+        }
+
+        let old_segments_count = self.segments.len();
+        let next_id = self.segments.last().map_or(1, |id| id + 1);
+        self.start_segment(next_id)?;
+        for ops in &frames {
+            self.append(ops, false)?;
+        }
+        self.active.sync_data()?;
+
+        for id in self.segments.drain(..old_segments_count).collect::<Vec<_>>() {
+            fs::remove_file(segment_path(&self.dir, id))?;
+        }
+        Ok(())
+    }
+
+    fn start_segment(&mut self, id: u64) -> Result<(), DBError> {
+        // frames appended without sync must be durable before their segment is left
+        self.active.sync_data()?;
+        self.active = OpenOptions::new().create(true).append(true).open(segment_path(&self.dir, id))?;
+        self.active_size = 0;
+        self.segments.push(id);
+        Ok(())
+    }
+
+    /// Append ops to the log as a single frame, optionally syncing it to disk
+    fn append(&mut self, ops: &[WriteOp], sync: bool) -> Result<(), DBError> {
+        if self.active_size >= self.max_segment_size {
+            let next_id = self.segments.last().map_or(1, |id| id + 1);
+            self.start_segment(next_id)?;
+        }
+
+        let payload = bincode::serialize(ops).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        self.active_size += write_frame(&mut self.active, self.active_size, &payload, sync)?;
+        Ok(())
+    }
+
+    /// Durably log ops, then apply them to the in-memory index
+    fn write_ops(&mut self, ops: Vec<WriteOp>) -> Result<(), DBError> {
+        self.append(&ops, true)?;
+        apply_ops(&mut self.index, ops);
+        Ok(())
+    }
+}
+
+fn apply_ops(index: &mut DB, ops: Vec<WriteOp>) {
+    for op in ops {
+        match op {
+            WriteOp::Put(name, key, value) => {
+                index.open_column_family(&name).inner.insert(IVec::from(key), IVec::from(value));
+            }
+            WriteOp::Delete(name, key) => {
+                if let Some(family) = index.families.get_mut(&name) {
+                    family.inner.remove(&IVec::from(key));
+                }
+            }
+            WriteOp::DeleteRange(name, from, to) => {
+                if let Some(family) = index.families.get_mut(&name) {
+                    family.remove_range(&IVec::from(from), &IVec::from(to));
+                }
+            }
+            WriteOp::DropColumnFamily(name) => {
+                index.drop_column_family(&name);
+            }
+        }
+    }
+}
+
+impl<S: KeyValueSchema> KeyValueStoreWithSchema<S> for PersistentDB {
+    fn put(&mut self, key: &S::Key, value: &S::Value) -> Result<(), DBError> {
+        if KeyValueStoreWithSchema::<S>::contains(&self.index, key)? {
+            return Err(DBError::KeyAlreadyExists);
+        }
+        self.write_ops(vec![WriteOp::Put(S::name().to_string(), key.encode()?, value.encode()?)])
+    }
+
+    fn delete(&mut self, key: &S::Key) -> Result<(), DBError> {
+        self.write_ops(vec![WriteOp::Delete(S::name().to_string(), key.encode()?)])
+    }
+
+    fn merge(&mut self, key: &S::Key, value: &S::Value) -> Result<(), DBError> {
+        // the merged value is logged, so replay does not depend on the merge operator
+        let key = key.encode()?;
+        let value = self.index.merged_value::<S>(&key, value)?;
+        self.write_ops(vec![WriteOp::Put(S::name().to_string(), key, value)])
+    }
+
+    fn get(&self, key: &S::Key) -> Result<Option<S::Value>, DBError> {
+        KeyValueStoreWithSchema::<S>::get(&self.index, key)
+    }
+
+    fn iterator(&self, mode: IteratorMode<S>) -> Result<IteratorWithSchema<'_, S>, DBError> {
+        self.index.iterator(mode)
+    }
+
+    fn prefix_iterator(&self, key: &S::Key) -> Result<IteratorWithSchema<'_, S>, DBError> {
+        KeyValueStoreWithSchema::<S>::prefix_iterator(&self.index, key)
+    }
+
+    fn contains(&self, key: &S::Key) -> Result<bool, DBError> {
+        KeyValueStoreWithSchema::<S>::contains(&self.index, key)
+    }
+
+    fn put_batch(&self, batch: &mut Batch, key: &S::Key, value: &S::Value) -> Result<(), DBError> {
+        KeyValueStoreWithSchema::<S>::put_batch(&self.index, batch, key, value)
+    }
+
+    fn put_if_absent_batch(&self, batch: &mut Batch, key: &S::Key, value: &S::Value) -> Result<(), DBError> {
+        KeyValueStoreWithSchema::<S>::put_if_absent_batch(&self.index, batch, key, value)
+    }
+
+    fn delete_batch(&self, batch: &mut Batch, key: &S::Key) -> Result<(), DBError> {
+        KeyValueStoreWithSchema::<S>::delete_batch(&self.index, batch, key)
+    }
+
+    fn delete_range_batch(&self, batch: &mut Batch, from: &S::Key, to: &S::Key) -> Result<(), DBError> {
+        KeyValueStoreWithSchema::<S>::delete_range_batch(&self.index, batch, from, to)
+    }
+
+    fn write_batch(&mut self, batch: Batch) -> Result<(), DBError> {
+        // conditions are checked against the current state before anything is logged, so replay
+        // only needs the resulting unconditional writes
+        self.index.check_batch(&batch)?;
+        let ops = batch.ops.into_iter()
+            .map(|op| match op {
+                BatchOp::Put(name, k, v) | BatchOp::PutIfAbsent(name, k, v) => WriteOp::Put(name, k.to_vec(), v.to_vec()),
+                BatchOp::Delete(name, k) => WriteOp::Delete(name, k.to_vec()),
+                BatchOp::DeleteRange(name, from, to) => WriteOp::DeleteRange(name, from.to_vec(), to.to_vec()),
+            })
+            .collect();
+        self.write_ops(ops)
+    }
+
+    fn get_mem_use_stats(&self) -> Result<DBStats, DBError> {
+        KeyValueStoreWithSchema::<S>::get_mem_use_stats(&self.index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ops::Bound;
+    use crate::database::Direction;
+    use crate::segment::test_dir;
+
+    struct TestSchema;
+
+    impl KeyValueSchema for TestSchema {
+        type Key = String;
+        type Value = String;
+
+        fn name() -> &'static str {
+            "test_schema"
+        }
+    }
+
+    fn set(db: &mut PersistentDB, key: &str, value: &str) {
+        KeyValueStoreWithSchema::<TestSchema>::merge(db, &key.to_string(), &value.to_string()).unwrap();
+    }
+
+    fn get(db: &PersistentDB, key: &str) -> Option<String> {
+        KeyValueStoreWithSchema::<TestSchema>::get(db, &key.to_string()).unwrap()
+    }
+
+    #[test]
+    fn test_reopen_replays_log() {
+        let dir = test_dir("persistent_db_reopen");
+        {
+            let mut db = PersistentDB::open(&dir).unwrap();
+            set(&mut db, "a", "1");
+            set(&mut db, "b", "2");
+            set(&mut db, "a", "3");
+            KeyValueStoreWithSchema::<TestSchema>::delete(&mut db, &"b".to_string()).unwrap();
+
+            let mut batch = Batch::default();
+            KeyValueStoreWithSchema::<TestSchema>::put_batch(&db, &mut batch, &"c".to_string(), &"4".to_string()).unwrap();
+            KeyValueStoreWithSchema::<TestSchema>::write_batch(&mut db, batch).unwrap();
+        }
+
+        let db = PersistentDB::open(&dir).unwrap();
+        assert_eq!(get(&db, "a"), Some("3".to_string()));
+        assert_eq!(get(&db, "b"), None);
+        assert_eq!(get(&db, "c"), Some("4".to_string()));
+    }
+
+    #[test]
+    fn test_segment_rotation_and_compaction() {
+        let dir = test_dir("persistent_db_rotation");
+        {
+            let mut db = PersistentDB::open_with_segment_size(&dir, 64).unwrap();
+            for i in 0..20 {
+                set(&mut db, "key", &i.to_string());
+            }
+            assert!(db.segments.len() > 1);
+
+            let size_before = db.disk_size().unwrap();
+            db.compact().unwrap();
+            assert_eq!(db.segments.len(), 1);
+            assert!(db.disk_size().unwrap() < size_before);
+
+            // frames are bounded by the segment size, so larger contents span several segments
+            for i in 0..20 {
+                set(&mut db, &format!("key{}", i), &i.to_string());
+            }
+            db.compact().unwrap();
+            assert!(db.segments.len() > 1);
+            assert_eq!(list_segments(&dir).unwrap(), db.segments);
+        }
+
+        let db = PersistentDB::open(&dir).unwrap();
+        assert!(list_segments(&dir).unwrap().len() > 1);
+        assert_eq!(get(&db, "key"), Some("19".to_string()));
+        for i in 0..20 {
+            assert_eq!(get(&db, &format!("key{}", i)), Some(i.to_string()));
+        }
+    }
+
+    #[test]
+    fn test_iterators() {
+        let dir = test_dir("persistent_db_iterators");
+        let mut db = PersistentDB::open(&dir).unwrap();
+        for key in &["a", "ab", "abc", "b", "c"] {
+            set(&mut db, key, &key.to_uppercase());
+        }
+
+        let keys = |iter: IteratorWithSchema<TestSchema>| iter.map(|(k, _)| k.unwrap()).collect::<Vec<_>>();
+        let (a, c) = ("a".to_string(), "c".to_string());
+        assert_eq!(keys(db.prefix_iterator(&a).unwrap()), vec!["a", "ab", "abc"]);
+        assert_eq!(keys(db.iterator(IteratorMode::Range(Bound::Excluded(&a), Bound::Included(&c), Direction::Reverse)).unwrap()),
+                   vec!["c", "b", "abc", "ab"]);
+        assert_eq!(keys(db.iterator(IteratorMode::End).unwrap()), vec!["c", "b", "abc", "ab", "a"]);
+
+        let (_, value) = db.iterator(IteratorMode::<TestSchema>::From(&"b".to_string(), Direction::Forward)).unwrap().next().unwrap();
+        assert_eq!(value.unwrap(), "B");
+    }
+
+    struct OtherSchema;
+
+    impl KeyValueSchema for OtherSchema {
+        type Key = String;
+        type Value = String;
+
+        fn name() -> &'static str {
+            "other_schema"
+        }
+    }
+
+    #[test]
+    fn test_column_families() {
+        let dir = test_dir("persistent_db_column_families");
+        {
+            let mut db = PersistentDB::open(&dir).unwrap();
+            set(&mut db, "a", "1");
+            KeyValueStoreWithSchema::<OtherSchema>::put(&mut db, &"a".to_string(), &"2".to_string()).unwrap();
+            KeyValueStoreWithSchema::<OtherSchema>::put(&mut db, &"b".to_string(), &"3".to_string()).unwrap();
+            assert_eq!(db.column_family_names(), vec!["other_schema", "test_schema"]);
+            db.compact().unwrap();
+        }
+
+        let mut db = PersistentDB::open(&dir).unwrap();
+        assert_eq!(get(&db, "a"), Some("1".to_string()));
+        assert_eq!(KeyValueStoreWithSchema::<OtherSchema>::get(&db, &"a".to_string()).unwrap(), Some("2".to_string()));
+        let stats = KeyValueStoreWithSchema::<OtherSchema>::get_mem_use_stats(&db).unwrap();
+        assert_eq!(stats.keys, 3);
+        assert_eq!(stats.column_families["other_schema"].keys, 2);
+
+        assert!(db.drop_column_family("other_schema").unwrap());
+        assert!(!db.drop_column_family("other_schema").unwrap());
+        // deleting from a missing column family does not create it
+        KeyValueStoreWithSchema::<OtherSchema>::delete(&mut db, &"a".to_string()).unwrap();
+        assert_eq!(db.column_family_names(), vec!["test_schema"]);
+        let db = PersistentDB::open(&dir).unwrap();
+        assert_eq!(db.column_family_names(), vec!["test_schema"]);
+        assert_eq!(KeyValueStoreWithSchema::<OtherSchema>::get(&db, &"a".to_string()).unwrap(), None);
+        assert_eq!(KeyValueStoreWithSchema::<OtherSchema>::iterator(&db, IteratorMode::Start).unwrap().count(), 0);
+        assert_eq!(get(&db, "a"), Some("1".to_string()));
+    }
+
+    #[test]
+    fn test_batch_deletes_and_conditions() {
+        let dir = test_dir("persistent_db_batch");
+        {
+            let mut db = PersistentDB::open(&dir).unwrap();
+            for key in &["a", "b", "c", "d"] {
+                set(&mut db, key, key);
+            }
+            let size = KeyValueStoreWithSchema::<TestSchema>::get_mem_use_stats(&db).unwrap().db_size;
+
+            let mut batch = Batch::default();
+            KeyValueStoreWithSchema::<TestSchema>::delete_batch(&db, &mut batch, &"a".to_string()).unwrap();
+            KeyValueStoreWithSchema::<TestSchema>::delete_range_batch(&db, &mut batch, &"b".to_string(), &"d".to_string()).unwrap();
+            // "c" was removed by the range earlier in the batch
+            KeyValueStoreWithSchema::<TestSchema>::put_if_absent_batch(&db, &mut batch, &"c".to_string(), &"C".to_string()).unwrap();
+            KeyValueStoreWithSchema::<TestSchema>::write_batch(&mut db, batch).unwrap();
+
+            let stats = KeyValueStoreWithSchema::<TestSchema>::get_mem_use_stats(&db).unwrap();
+            assert_eq!(stats.keys, 2);
+            assert!(stats.db_size < size);
+
+            // conflicting insert fails the whole batch
+            let mut batch = Batch::default();
+            KeyValueStoreWithSchema::<TestSchema>::delete_batch(&db, &mut batch, &"d".to_string()).unwrap();
+            KeyValueStoreWithSchema::<TestSchema>::put_if_absent_batch(&db, &mut batch, &"c".to_string(), &"X".to_string()).unwrap();
+            let disk_size = db.disk_size().unwrap();
+            assert!(matches!(KeyValueStoreWithSchema::<TestSchema>::write_batch(&mut db, batch), Err(DBError::KeyAlreadyExists)));
+            assert_eq!(db.disk_size().unwrap(), disk_size);
+            assert_eq!(get(&db, "d"), Some("d".to_string()));
+        }
+
+        let db = PersistentDB::open(&dir).unwrap();
+        let keys = KeyValueStoreWithSchema::<TestSchema>::iterator(&db, IteratorMode::Start).unwrap()
+            .map(|(k, v)| (k.unwrap(), v.unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(keys, vec![("c".to_string(), "C".to_string()), ("d".to_string(), "d".to_string())]);
+    }
+
+    struct CounterSchema;
+
+    impl KeyValueSchema for CounterSchema {
+        type Key = String;
+        type Value = u64;
+
+        fn name() -> &'static str {
+            "counter_schema"
+        }
+
+        fn merge_values(existing: Option<u64>, value: &u64) -> Option<u64> {
+            existing.map(|existing| existing + value)
+        }
+    }
+
+    #[test]
+    fn test_put_and_merge() {
+        let dir = test_dir("persistent_db_put_and_merge");
+        let key = "a".to_string();
+        {
+            let mut db = PersistentDB::open(&dir).unwrap();
+            KeyValueStoreWithSchema::<TestSchema>::put(&mut db, &key, &"1".to_string()).unwrap();
+            let result = KeyValueStoreWithSchema::<TestSchema>::put(&mut db, &key, &"2".to_string());
+            assert!(matches!(result, Err(DBError::KeyAlreadyExists)));
+            assert_eq!(get(&db, "a"), Some("1".to_string()));
+
+            // default merge overrides the value
+            set(&mut db, "a", "3");
+            assert_eq!(get(&db, "a"), Some("3".to_string()));
+
+            for i in 1..=4 {
+                KeyValueStoreWithSchema::<CounterSchema>::merge(&mut db, &key, &i).unwrap();
+            }
+            assert_eq!(KeyValueStoreWithSchema::<CounterSchema>::get(&db, &key).unwrap(), Some(10));
+        }
+
+        let db = PersistentDB::open(&dir).unwrap();
+        assert_eq!(KeyValueStoreWithSchema::<CounterSchema>::get(&db, &key).unwrap(), Some(10));
+    }
+
+    #[test]
+    fn test_torn_write_is_discarded() {
+        let dir = test_dir("persistent_db_torn_write");
+        {
+            let mut db = PersistentDB::open(&dir).unwrap();
+            set(&mut db, "a", "1");
+            set(&mut db, "b", "2");
+        }
+
+        // simulate crash in the middle of writing the last frame
+        let path = segment_path(&dir, 1);
+        let len = fs::metadata(&path).unwrap().len();
+        OpenOptions::new().write(true).open(&path).unwrap().set_len(len - 3).unwrap();
+
+        let mut db = PersistentDB::open(&dir).unwrap();
+        assert_eq!(get(&db, "a"), Some("1".to_string()));
+        assert_eq!(get(&db, "b"), None);
+
+        set(&mut db, "c", "3");
+        let db = PersistentDB::open(&dir).unwrap();
+        assert_eq!(get(&db, "c"), Some("3".to_string()));
+    }
+}
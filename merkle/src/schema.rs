@@ -11,6 +11,13 @@ pub trait KeyValueSchema {
     type Value: Codec;
 
     fn name() -> &'static str;
+
+    /// Merge operator used by `KeyValueStoreWithSchema::merge`, combines the `existing` value of a
+    /// key (if any) with the incoming `value`. Returning `None` stores `value` as it is, which is
+    /// the default.
+    fn merge_values(_existing: Option<Self::Value>, _value: &Self::Value) -> Option<Self::Value> {
+        None
+    }
 }
 
 pub struct CommitLogDescriptor {
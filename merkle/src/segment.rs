@@ -0,0 +1,179 @@
+//! # Segments
+//!
+//! Append-only segment files shared by `PersistentDB` and `CommitLogStore`. A store keeps its
+//! segments in one directory, named by a number identifying the segment
+//! (`00000000000000000001.log`, ...), each being a sequence of frames:
+//!
+//! ```no_compile
+//! [payload_len: u32][checksum: blake2b-128 of payload][payload]
+//! ```
+//!
+//! Frames are only appended to the last segment, so an incomplete or corrupted frame is only
+//! expected at its end, as the result of a crash in the middle of a write.
+use std::convert::TryFrom;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::blake2b;
+use crate::database::DBError;
+
+/// Segment size after which a new segment file is started
+pub const DEFAULT_MAX_SEGMENT_SIZE: u64 = 64 * 1024 * 1024;
+
+const SEGMENT_EXTENSION: &str = "log";
+const CHECKSUM_LEN: usize = 16;
+pub(crate) const FRAME_HEADER_LEN: u64 = 4 + CHECKSUM_LEN as u64;
+
+pub(crate) fn segment_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{:020}.{}", id, SEGMENT_EXTENSION))
+}
+
+/// Numbers of all segment files in `dir`, in ascending order
+pub(crate) fn list_segments(dir: &Path) -> Result<Vec<u64>, DBError> {
+    let mut segments = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some(SEGMENT_EXTENSION) {
+            continue;
+        }
+        if let Some(id) = path.file_stem().and_then(|stem| stem.to_str()).and_then(|stem| stem.parse::<u64>().ok()) {
+            segments.push(id);
+        }
+    }
+    segments.sort_unstable();
+    Ok(segments)
+}
+
+/// Append a frame of `payload` to the segment `file` holding `size` bytes of valid frames,
+/// returning the length of the frame. If the write fails, the partial frame is cut off again,
+/// as it would otherwise hide all frames written after it. Payloads longer than `u32::MAX`
+/// bytes are rejected, as their length would not fit into the header.
+pub(crate) fn write_frame(file: &mut File, size: u64, payload: &[u8], sync: bool) -> Result<u64, DBError> {
+    let payload_len = u32::try_from(payload.len()).map_err(|_| io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("frame payload of {} bytes is too large", payload.len()),
+    ))?;
+    let mut frame = Vec::with_capacity(FRAME_HEADER_LEN as usize + payload.len());
+    frame.extend_from_slice(&payload_len.to_be_bytes());
+    frame.extend_from_slice(&blake2b::digest_128(payload));
+    frame.extend_from_slice(payload);
+
+    let result = file.write_all(&frame).and_then(|_| if sync { file.sync_data() } else { Ok(()) });
+    if let Err(error) = result {
+        file.set_len(size)?;
+        return Err(error.into());
+    }
+    Ok(frame.len() as u64)
+}
+
+/// Read a frame, returning its payload, or `None` at the end of data
+pub(crate) fn read_frame<R: Read>(reader: &mut R) -> Result<Option<Vec<u8>>, DBError> {
+    let mut header = [0u8; FRAME_HEADER_LEN as usize];
+    match reader.read_exact(&mut header) {
+        Ok(()) => (),
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let payload_len = crate::num_from_slice!(header, 0, u32) as usize;
+    // a torn or corrupted length must not cause a huge allocation up front
+    let mut payload = Vec::new();
+    reader.by_ref().take(payload_len as u64).read_to_end(&mut payload)?;
+    if payload.len() != payload_len {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "incomplete frame").into());
+    }
+    if blake2b::digest_128(&payload) != header[4..] {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "frame checksum mismatch").into());
+    }
+    Ok(Some(payload))
+}
+
+/// Pass payloads of all frames of the segment at `path` to `f`, returning the size of the valid
+/// frames. An incomplete or corrupted frame is only tolerated at the end of the last segment,
+/// where it is cut off.
+pub(crate) fn replay_segment<F>(path: &Path, is_last: bool, mut f: F) -> Result<u64, DBError>
+    where
+        F: FnMut(Vec<u8>) -> Result<(), DBError>,
+{
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut size = 0;
+    loop {
+        match read_frame(&mut reader) {
+            Ok(Some(payload)) => {
+                size += FRAME_HEADER_LEN + payload.len() as u64;
+                f(payload)?;
+            }
+            Ok(None) => break,
+            Err(DBError::IOError { ref error }) if error.kind() == io::ErrorKind::InvalidData => break,
+            Err(err) => return Err(err),
+        }
+    }
+
+    if size < fs::metadata(path)?.len() {
+        if !is_last {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("corrupted frame at offset {} in {}", size, path.display()),
+            ).into());
+        }
+        OpenOptions::new().write(true).open(path)?.set_len(size)?;
+    }
+    Ok(size)
+}
+
+#[cfg(test)]
+pub(crate) fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join("merkle_segment_tests").join(name);
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open_segment(dir: &Path, id: u64) -> File {
+        OpenOptions::new().create(true).append(true).open(segment_path(dir, id)).unwrap()
+    }
+
+    fn payloads(dir: &Path, id: u64, is_last: bool) -> Result<Vec<Vec<u8>>, DBError> {
+        let mut payloads = Vec::new();
+        replay_segment(&segment_path(dir, id), is_last, |payload| {
+            payloads.push(payload);
+            Ok(())
+        })?;
+        Ok(payloads)
+    }
+
+    #[test]
+    fn test_write_and_replay() {
+        let dir = test_dir("write_and_replay");
+        fs::create_dir_all(&dir).unwrap();
+        let mut file = open_segment(&dir, 7);
+        let a = write_frame(&mut file, 0, b"a", true).unwrap();
+        write_frame(&mut file, a, b"bb", false).unwrap();
+        open_segment(&dir, 3);
+
+        assert_eq!(list_segments(&dir).unwrap(), vec![3, 7]);
+        assert_eq!(payloads(&dir, 7, true).unwrap(), vec![b"a".to_vec(), b"bb".to_vec()]);
+        assert!(payloads(&dir, 3, true).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_torn_tail_is_cut_off() {
+        let dir = test_dir("torn_tail");
+        fs::create_dir_all(&dir).unwrap();
+        let mut file = open_segment(&dir, 1);
+        let a = write_frame(&mut file, 0, b"a", true).unwrap();
+        write_frame(&mut file, a, b"bb", true).unwrap();
+
+        // simulate crash in the middle of writing the last frame
+        let path = segment_path(&dir, 1);
+        let len = fs::metadata(&path).unwrap().len();
+        file.set_len(len - 1).unwrap();
+
+        assert!(payloads(&dir, 1, false).is_err());
+        assert_eq!(payloads(&dir, 1, true).unwrap(), vec![b"a".to_vec()]);
+        assert_eq!(fs::metadata(&path).unwrap().len(), a);
+    }
+}